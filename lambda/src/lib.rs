@@ -0,0 +1,239 @@
+//! AWS X-Ray tracing integration for AWS Lambda runtime handlers
+//!
+//! Wrap a handler with `traced` to record each invocation as a subsegment of the
+//! segment Lambda records for it.
+//!
+//! ```rust,no_run
+//! use lambda_runtime::{error::HandlerError, lambda, Context};
+//!
+//! fn handler(
+//!     event: String,
+//!     _: Context,
+//! ) -> Result<String, HandlerError> {
+//!     Ok(event)
+//! }
+//!
+//! fn main() {
+//!     lambda!(xray_lambda::traced(handler))
+//! }
+//! ```
+
+use lambda_runtime::{error::HandlerError, Context, Handler};
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use xray::{context, lambda, Cause, Client};
+
+/// True until the first invocation of this process
+static COLD_START: AtomicBool = AtomicBool::new(true);
+
+/// A Lambda runtime handler which records its invocations with X-Ray
+pub struct Traced<H> {
+    handler: H,
+    client: Arc<Client>,
+}
+
+/// Wrap a handler with a default X-Ray client
+pub fn traced<H>(handler: H) -> Traced<H> {
+    Traced::new_with_client(handler, Arc::new(Client::default()))
+}
+
+impl<H> Traced<H> {
+    /// Wrap a handler with a custom X-Ray client
+    pub fn new_with_client(
+        handler: H,
+        client: Arc<Client>,
+    ) -> Self {
+        Traced { handler, client }
+    }
+}
+
+impl<H, E, O> Handler<E, O> for Traced<H>
+where
+    H: Handler<E, O>,
+{
+    fn run(
+        &mut self,
+        event: E,
+        ctx: Context,
+    ) -> Result<O, HandlerError> {
+        let cold_start = COLD_START.swap(false, Ordering::SeqCst);
+        let mut facade = match lambda::facade(&ctx.xray_trace_id) {
+            Some(facade) => facade,
+            None => return self.handler.run(event, ctx),
+        };
+        let mut subsegment = facade.begin_subsegment(ctx.function_name.as_str());
+        subsegment
+            .annotate("cold_start", cold_start)
+            .annotate("function_arn", ctx.invoked_function_arn.as_str());
+
+        let entered = context::enter(subsegment);
+        let result = self.handler.run(event, ctx);
+        // the panic hook may already have flushed the subsegment
+        if let Some(context::Active::Subsegment(mut subsegment)) = entered.exit() {
+            if let Err(err) = &result {
+                subsegment.record_fault(Cause::from_fail(err));
+            }
+            facade.end_subsegment(subsegment).end();
+            // the invocation response is posted once this returns
+            if let Err(err) = self.client.send_document(&facade) {
+                log::debug!("Failed to send invocation subsegment: {}", err);
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::Value;
+    use std::{
+        env,
+        io::{BufRead, BufReader, Read, Write},
+        net::{TcpListener, TcpStream, UdpSocket},
+        sync::mpsc::{channel, Sender},
+        thread,
+        time::Duration,
+    };
+
+    const TRACE_ID: &str =
+        "Root=1-5759e988-bd862e3fe1be46a994272793;Parent=53995c3f42cd8ad8;Sampled=1";
+    const FUNCTION_ARN: &str = "arn:aws:lambda:us-east-1:123456789012:function:test";
+
+    /// Serve a single invocation from a fake Lambda Runtime API, sending the posted
+    /// response path and body
+    fn runtime_api(responses: Sender<(String, String)>) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("failed to bind listener");
+        let addr = listener.local_addr().expect("failed to get address");
+        thread::spawn(move || {
+            let mut invoked = false;
+            for stream in listener.incoming() {
+                let stream = stream.expect("failed to accept connection");
+                let responses = responses.clone();
+                let first = !invoked;
+                invoked = true;
+                thread::spawn(move || serve(stream, first, &responses));
+            }
+        });
+        addr.to_string()
+    }
+
+    fn serve(
+        stream: TcpStream,
+        mut invoke: bool,
+        responses: &Sender<(String, String)>,
+    ) {
+        let mut reader = BufReader::new(stream.try_clone().expect("failed to clone stream"));
+        let mut stream = stream;
+        loop {
+            let mut request_line = String::new();
+            if reader.read_line(&mut request_line).unwrap_or(0) == 0 {
+                return;
+            }
+            let mut content_length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).expect("failed to read header");
+                if line.trim().is_empty() {
+                    break;
+                }
+                let line = line.to_lowercase();
+                if line.starts_with("content-length:") {
+                    content_length = line
+                        .trim_start_matches("content-length:")
+                        .trim()
+                        .parse()
+                        .unwrap_or(0);
+                }
+            }
+            let mut body = vec![0; content_length];
+            reader.read_exact(&mut body).expect("failed to read body");
+            let path = request_line
+                .split_whitespace()
+                .nth(1)
+                .unwrap_or_default()
+                .to_string();
+            if path.ends_with("/invocation/next") {
+                if !invoke {
+                    // block further invocations until the test ends
+                    thread::sleep(Duration::from_secs(60));
+                    return;
+                }
+                invoke = false;
+                let event = r#""event""#;
+                write!(
+                    stream,
+                    "HTTP/1.1 200 OK\r\nLambda-Runtime-Aws-Request-Id: 8476a536\r\nLambda-Runtime-Deadline-Ms: 4102444800000\r\nLambda-Runtime-Invoked-Function-Arn: {}\r\nLambda-Runtime-Trace-Id: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\n\r\n{}",
+                    FUNCTION_ARN,
+                    TRACE_ID,
+                    event.len(),
+                    event
+                )
+                .expect("failed to write invocation");
+            } else {
+                responses
+                    .send((path, String::from_utf8_lossy(&body).to_string()))
+                    .expect("failed to send response");
+                write!(stream, "HTTP/1.1 202 Accepted\r\nContent-Length: 0\r\n\r\n")
+                    .expect("failed to write acknowledgement");
+            }
+        }
+    }
+
+    fn received(daemon: &UdpSocket) -> Value {
+        let mut buf = [0; 8192];
+        let len = daemon.recv(&mut buf).expect("failed to receive document");
+        let packet = String::from_utf8_lossy(&buf[..len]).to_string();
+        let (_, body) = packet.split_at(packet.find("\\n").expect("no header") + 2);
+        serde_json::from_str(body).expect("invalid document")
+    }
+
+    #[test]
+    fn invocations_are_traced_against_the_runtime_api() {
+        let daemon = UdpSocket::bind("127.0.0.1:0").expect("failed to bind socket");
+        daemon
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .expect("failed to set timeout");
+        let client = Arc::new(
+            Client::new(daemon.local_addr().expect("failed to get address"))
+                .expect("failed to create client"),
+        );
+        let (sender, responses) = channel();
+        env::set_var("AWS_LAMBDA_RUNTIME_API", runtime_api(sender));
+        env::set_var("AWS_LAMBDA_FUNCTION_NAME", "test");
+        env::set_var("AWS_LAMBDA_FUNCTION_VERSION", "$LATEST");
+        env::set_var("AWS_LAMBDA_FUNCTION_MEMORY_SIZE", "128");
+        env::set_var("AWS_LAMBDA_LOG_STREAM_NAME", "stream");
+        env::set_var("AWS_LAMBDA_LOG_GROUP_NAME", "group");
+
+        thread::spawn(move || {
+            lambda_runtime::start(
+                Traced::new_with_client(
+                    |_: String, ctx: Context| -> Result<String, HandlerError> {
+                        Err(ctx.new_error("boom"))
+                    },
+                    client,
+                ),
+                None,
+            )
+        });
+
+        let subsegment = received(&daemon);
+        let (path, _) = responses
+            .recv_timeout(Duration::from_secs(5))
+            .expect("no invocation response");
+        assert!(path.ends_with("/invocation/8476a536/error"));
+        assert_eq!(subsegment["name"], "test");
+        assert_eq!(
+            subsegment["trace_id"],
+            "1-5759e988-bd862e3fe1be46a994272793"
+        );
+        assert_eq!(subsegment["parent_id"], "53995c3f42cd8ad8");
+        assert_eq!(subsegment["annotations"]["cold_start"], true);
+        assert_eq!(subsegment["annotations"]["function_arn"], FUNCTION_ARN);
+        assert_eq!(subsegment["fault"], true);
+        assert_eq!(subsegment["cause"]["exceptions"][0]["message"], "boom");
+    }
+}
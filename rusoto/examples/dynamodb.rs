@@ -1,18 +1,35 @@
-use rusoto_core::{DefaultCredentialsProvider, Region};
+use rusoto_core::{request::HttpClient, DefaultCredentialsProvider, Region};
 use rusoto_dynamodb::{DynamoDb, DynamoDbClient, ListTablesInput};
+use std::sync::Arc;
 use tokio::runtime::Runtime;
-use xray_rusoto::TracedRequests;
+use xray::{Client, Segment};
+use xray_rusoto::{scoped, TracedRequests};
 
 fn main() {
     let mut rt = Runtime::new().expect("failed to initialize runtime");
+    let xray = Arc::new(Client::default());
     let client = DynamoDbClient::new_with(
-        TracedRequests::default(),
+        TracedRequests::new_with_client(
+            HttpClient::new().expect("failed to initialize client"),
+            xray.clone(),
+        ),
         DefaultCredentialsProvider::new().expect("failed to initialize credential provider"),
         Region::default(),
     );
     //let client = DynamoDbClient::new(Region::default());
-    println!(
-        "{:#?}",
-        rt.block_on(client.list_tables(ListTablesInput::default()))
-    );
+    // the segment is active on whichever pool thread polls the request
+    let (tables, segment) = match rt.block_on(scoped(
+        Segment::begin("dynamodb-example"),
+        client.list_tables(ListTablesInput::default()),
+    )) {
+        Ok((tables, segment)) => (Ok(tables), segment),
+        Err((err, segment)) => (Err(err), segment),
+    };
+    if let Some(mut segment) = segment {
+        segment.end();
+        if let Err(err) = xray.send_document(&segment) {
+            eprintln!("failed to send segment: {}", err);
+        }
+    }
+    println!("{:#?}", tables);
 }
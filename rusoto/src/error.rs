@@ -0,0 +1,180 @@
+//! Errors AWS services describe in response bodies
+//!
+//! JSON protocol services return errors like
+//! `{"__type":"com.amazonaws.dynamodb.v20120810#ResourceNotFoundException","message":"..."}`,
+//! while query and REST XML protocol services return `<Error>` elements carrying
+//! `<Code>` and `<Message>` elements
+
+use serde_json::Value;
+use std::collections::HashMap;
+use xray::Exception;
+
+/// Error codes AWS services return when throttling requests
+const THROTTLING_CODES: &[&str] = &[
+    "BandwidthLimitExceeded",
+    "EC2ThrottledException",
+    "LimitExceededException",
+    "PriorRequestNotComplete",
+    "ProvisionedThroughputExceededException",
+    "RequestLimitExceeded",
+    "RequestThrottled",
+    "RequestThrottledException",
+    "SlowDown",
+    "ThrottledException",
+    "Throttling",
+    "ThrottlingException",
+    "TooManyRequestsException",
+    "TransactionInProgressException",
+];
+
+/// An error returned by an AWS service
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct ServiceError {
+    pub(crate) code: String,
+    pub(crate) message: Option<String>,
+}
+
+impl ServiceError {
+    /// Return the error described by a response's headers and body, if any
+    pub(crate) fn from_response(
+        headers: &HashMap<String, String>,
+        body: &[u8],
+    ) -> Option<Self> {
+        let json = serde_json::from_slice::<Value>(body).ok();
+        let code = headers
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case("x-amzn-errortype"))
+            .map(|(_, value)| value.clone())
+            .or_else(|| {
+                json.as_ref()
+                    .and_then(|json| field(json, &["__type", "code", "Code"]))
+            })
+            .or_else(|| element(body, "Code"))
+            .map(|code| short_code(&code))
+            .filter(|code| !code.is_empty())?;
+        let message = match json {
+            Some(json) => field(&json, &["message", "Message", "errorMessage"]),
+            _ => element(body, "Message"),
+        };
+        Some(ServiceError { code, message })
+    }
+
+    /// Return true if the error signals the request was throttled
+    pub(crate) fn is_throttling(&self) -> bool {
+        THROTTLING_CODES.contains(&self.code.as_str())
+    }
+
+    /// Describe the error as an exception raised by the remote service
+    pub(crate) fn exception(&self) -> Exception {
+        let mut exception = Exception::new(self.code.clone());
+        exception.message = self.message.clone();
+        exception.type_ = Some(self.code.clone());
+        exception.remote = Some(true);
+        exception
+    }
+}
+
+/// Return the first of a JSON object's string fields present
+fn field(
+    json: &Value,
+    names: &[&str],
+) -> Option<String> {
+    names
+        .iter()
+        .find_map(|name| json.get(name).and_then(Value::as_str))
+        .map(str::to_string)
+}
+
+/// Return the text of the first XML element named `name`
+fn element(
+    body: &[u8],
+    name: &str,
+) -> Option<String> {
+    let body = String::from_utf8_lossy(body);
+    let (open, close) = (format!("<{}>", name), format!("</{}>", name));
+    let start = body.find(&open)? + open.len();
+    let end = body[start..].find(&close)? + start;
+    Some(unescape(body[start..end].trim()))
+}
+
+fn unescape(text: &str) -> String {
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+/// Strip namespaces and documentation links from error codes, for example
+/// `ResourceNotFoundException` from `com.amazonaws.dynamodb.v20120810#ResourceNotFoundException`
+/// or `ValidationException:http://internal.amazon.com/coral/com.amazon.coral.validate/`
+fn short_code(code: &str) -> String {
+    let code = code.rsplit('#').next().unwrap_or_default();
+    code.split(':')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn json_errors_are_parsed() {
+        let error = ServiceError::from_response(
+            &HashMap::new(),
+            br#"{"__type":"com.amazonaws.dynamodb.v20120810#ProvisionedThroughputExceededException","message":"Rate exceeded"}"#,
+        )
+        .expect("no error");
+        assert_eq!(error.code, "ProvisionedThroughputExceededException");
+        assert_eq!(error.message, Some("Rate exceeded".into()));
+        assert!(error.is_throttling());
+    }
+
+    #[test]
+    fn error_type_headers_take_precedence() {
+        let headers = vec![(
+            "x-amzn-ErrorType".to_string(),
+            "ResourceNotFoundException:http://internal.amazon.com/coral/".to_string(),
+        )]
+        .into_iter()
+        .collect();
+        let error = ServiceError::from_response(&headers, br#"{"Message":"Function not found"}"#)
+            .expect("no error");
+        assert_eq!(error.code, "ResourceNotFoundException");
+        assert_eq!(error.message, Some("Function not found".into()));
+        assert!(!error.is_throttling());
+    }
+
+    #[test]
+    fn xml_errors_are_parsed() {
+        let error = ServiceError::from_response(
+            &HashMap::new(),
+            b"<ErrorResponse><Error><Type>Sender</Type><Code>Throttling</Code><Message>Rate &amp; burst exceeded</Message></Error><RequestId>8476a536</RequestId></ErrorResponse>",
+        )
+        .expect("no error");
+        assert_eq!(error.code, "Throttling");
+        assert_eq!(error.message, Some("Rate & burst exceeded".into()));
+        assert!(error.is_throttling());
+    }
+
+    #[test]
+    fn other_bodies_are_not_errors() {
+        assert!(ServiceError::from_response(&HashMap::new(), b"").is_none());
+        assert!(ServiceError::from_response(&HashMap::new(), br#"{"Item":{}}"#).is_none());
+    }
+
+    #[test]
+    fn exceptions_are_remote() {
+        let exception = ServiceError {
+            code: "NoSuchKey".into(),
+            message: None,
+        }
+        .exception();
+        assert_eq!(exception.type_, Some("NoSuchKey".into()));
+        assert_eq!(exception.remote, Some(true));
+        assert!(exception.message.is_none());
+    }
+}
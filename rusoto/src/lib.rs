@@ -1,17 +1,30 @@
 //! AWS X-Ray tracing integration for for the Rusoto AWS SDK
 
-use futures::Future;
+use crate::{error::ServiceError, operation::Parameters};
+use futures::{Async, Future, Poll, Stream};
 use rusoto_core::{
     request::{HttpClient, HttpDispatchError, HttpResponse},
     signature::SignedRequest,
-    DispatchSignedRequest,
+    ByteStream, DispatchSignedRequest,
+};
+use std::{collections::HashMap, sync::Arc, time::Duration};
+use xray::{context, Cause, Client, Header, Http, RecursionGuard, Response, Subsegment};
+
+mod error;
+mod manifest;
+mod operation;
+mod scoped;
+
+pub use crate::{
+    manifest::Manifest,
+    scoped::{scoped, Scoped},
 };
-use std::{sync::Arc, time::Duration};
-use xray::{Client, Segment, Subsegment};
 
 pub struct TracedRequests<D> {
     dispatcher: D,
     client: Arc<Client>,
+    manifest: Arc<Manifest>,
+    recursion_guard: RecursionGuard,
 }
 
 impl<D> TracedRequests<D> {
@@ -25,7 +38,32 @@ impl<D> TracedRequests<D> {
         dispatcher: D,
         client: Arc<Client>,
     ) -> Self {
-        Self { dispatcher, client }
+        Self {
+            dispatcher,
+            client,
+            manifest: Arc::new(Manifest::default()),
+            recursion_guard: RecursionGuard::default(),
+        }
+    }
+
+    /// Refuse requests of traces whose lineage is deeper than `recursion_guard` allows
+    /// instead of the default guard
+    pub fn with_recursion_guard(
+        mut self,
+        recursion_guard: RecursionGuard,
+    ) -> Self {
+        self.recursion_guard = recursion_guard;
+        self
+    }
+
+    /// Record the request and response parameters of a custom manifest
+    /// instead of the default one
+    pub fn with_manifest(
+        mut self,
+        manifest: Manifest,
+    ) -> Self {
+        self.manifest = Arc::new(manifest);
+        self
     }
 }
 
@@ -38,6 +76,29 @@ impl Default for TracedRequests<HttpClient> {
 /// Implementation of DispatchSignedRequest which wraps
 /// an implementation of another DispatchSignedRequest
 /// with a tracing future
+///
+/// Requests are traced as subsegments of the segment or subsegment active on the
+/// dispatching thread, see `xray::context::enter`. Rusoto dispatches requests when
+/// their futures are first polled, so futures run on thread pools should make their
+/// document active on every poll with `scoped`. Requests dispatched without an
+/// active document are not traced
+///
+/// Traced requests carry an `X-Amzn-Trace-Id` header so AWS services can continue the
+/// trace. Requests are signed before they are dispatched, so the header is left out of
+/// their signed headers and signatures stay valid. Requests whose header carries a lineage
+/// deeper than the recursion guard allows are refused with an error, and never dispatched
+///
+/// The parameters the manifest lists for an operation are recorded in the subsegment's
+/// `aws` field. Responses of traced requests are read in full before they are returned
+/// when response parameters are recorded from JSON protocol bodies, and when their status
+/// is an error status. Other responses, such as Lambda invocation payloads, are returned
+/// unread with their parameters recorded from headers. Errors AWS describes in response
+/// bodies are recorded as remote exceptions
+///
+/// Each request dispatched is traced as a subsegment of its own and is only dispatched once,
+/// so requests the caller retries are recorded once per attempt. Subsegments sent while
+/// their parent is still active are recorded on it with `complete_child`, so later requests
+/// list them among their `precursor_ids`
 impl<D> DispatchSignedRequest for TracedRequests<D>
 where
     D: DispatchSignedRequest + Send + Sync + 'static,
@@ -46,23 +107,143 @@ where
     type Future = TracingRequest<D::Future>;
     fn dispatch(
         &self,
-        request: SignedRequest,
+        mut request: SignedRequest,
         timeout: Option<Duration>,
     ) -> Self::Future {
-        let segment = Segment::begin("test");
-        let mut subsegment =
-            Subsegment::begin(segment.trace_id.clone(), None, request.service.as_str());
-        subsegment.namespace = Some("aws".into());
-        TracingRequest(
-            self.dispatcher.dispatch(request, timeout),
+        let mut buffered = false;
+        let subsegment = context::with_current(|active| {
+            let mut subsegment = active.begin_subsegment(request.service.as_str());
+            subsegment.namespace = Some("aws".into());
+            if subsegment.is_sampled() {
+                let parameters = Parameters::from_request(&request);
+                let mut aws = operation::operation(&request, &parameters);
+                let recorded = aws
+                    .operation
+                    .as_ref()
+                    .and_then(|operation| self.manifest.operation(&request.service, operation));
+                if let Some(recorded) = recorded {
+                    recorded.record_request(&parameters, &mut aws);
+                    buffered = recorded.records_response();
+                }
+                subsegment.aws = Some(aws);
+            }
+            subsegment
+        });
+        let mut refused = None;
+        if let Some(header) = subsegment.as_ref().and_then(Subsegment::header) {
+            match self.recursion_guard.check(&header) {
+                Ok(()) => {
+                    request.add_header(Header::NAME, &header.to_string_limited(Header::MAX_LEN))
+                }
+                Err(err) => refused = Some(HttpDispatchError::new(err.to_string())),
+            }
+        }
+        TracingRequest {
+            request: if refused.is_none() {
+                Some(self.dispatcher.dispatch(request, timeout))
+            } else {
+                None
+            },
+            refused,
             subsegment,
-            self.client.clone(),
-        )
+            client: self.client.clone(),
+            manifest: self.manifest.clone(),
+            buffered,
+            buffering: None,
+        }
     }
 }
 
 /** a dispatching request that will be traced if x-ray trace is sampled */
-pub struct TracingRequest<T>(T, Subsegment, Arc<Client>);
+pub struct TracingRequest<T> {
+    /// The dispatched request, unless it was refused
+    request: Option<T>,
+    /// Why the request was refused without being dispatched
+    refused: Option<HttpDispatchError>,
+    subsegment: Option<Subsegment>,
+    client: Arc<Client>,
+    manifest: Arc<Manifest>,
+    /// Whether response parameters are recorded, reading JSON protocol responses in full
+    buffered: bool,
+    /// A response whose body is being read, and the body read so far
+    buffering: Option<(HttpResponse, Vec<u8>)>,
+}
+
+impl<T> TracingRequest<T> {
+    /// Return true if the request is traced by a sampled subsegment
+    fn sampled(&self) -> bool {
+        self.subsegment
+            .as_ref()
+            .map_or(false, Subsegment::is_sampled)
+    }
+
+    /// Record the parameters of a response the manifest lists
+    fn record_parameters(
+        &mut self,
+        response: &HttpResponse,
+        body: &[u8],
+    ) {
+        let subsegment = match self.subsegment.as_mut() {
+            Some(subsegment) => subsegment,
+            _ => return,
+        };
+        let service = subsegment.name().to_string();
+        if let Some(aws) = subsegment.aws.as_mut() {
+            let recorded = aws
+                .operation
+                .as_ref()
+                .and_then(|operation| self.manifest.operation(&service, operation));
+            if let Some(recorded) = recorded {
+                recorded.record_response(&Parameters::from_response(response, body), aws);
+            }
+        }
+    }
+
+    /// End the subsegment with the outcome of the request and send it
+    fn finish(
+        &mut self,
+        result: Result<(&HttpResponse, Option<&ServiceError>), &HttpDispatchError>,
+    ) {
+        let (subsegment, client) = match self {
+            TracingRequest {
+                subsegment: Some(subsegment),
+                client,
+                ..
+            } if subsegment.is_sampled() => (subsegment, client),
+            _ => return,
+        };
+        match result {
+            Ok((response, error)) => {
+                record_response(
+                    subsegment,
+                    response.status.as_u16(),
+                    content_length(&response.headers),
+                    error.map_or(false, ServiceError::is_throttling),
+                );
+                if let Some(error) = error {
+                    subsegment.cause = Some(Cause::from_exceptions(vec![error.exception()]));
+                }
+                if let Some(aws) = subsegment.aws.as_mut() {
+                    aws.request_id = operation::request_id(&response.headers);
+                }
+            }
+            Err(err) => {
+                subsegment.record_fault(Cause::from_error(err));
+            }
+        }
+        subsegment.end();
+        if let Err(err) = client.send_document(&*subsegment) {
+            log::debug!("Failed to send subsegment: {}", err);
+            return;
+        }
+        // let later siblings list this subsegment among their precursors
+        context::with_current(|active| {
+            if Some(active.id()) == subsegment.parent_id.as_ref() {
+                active.complete_child(subsegment.id());
+            }
+        });
+    }
+}
 
 impl<T> Future for TracingRequest<T>
 where
@@ -70,14 +251,558 @@ where
 {
     type Item = HttpResponse;
     type Error = HttpDispatchError;
-    fn poll(&mut self) -> futures::Poll<Self::Item, Self::Error> {
-        match self.0.poll() {
-            Ok(futures::Async::Ready(res)) => {
-                // todo: add tracing
-                Ok(futures::Async::Ready(res))
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if let Some(err) = self.refused.take() {
+            self.finish(Err(&err));
+            return Err(err);
+        }
+        loop {
+            if let Some((response, body)) = self.buffering.as_mut() {
+                match response.body.poll() {
+                    Ok(Async::Ready(Some(chunk))) => {
+                        body.extend_from_slice(&chunk);
+                        continue;
+                    }
+                    Ok(Async::Ready(None)) => {
+                        let (mut response, body) = self.buffering.take().expect("no response");
+                        let status = response.status.as_u16();
+                        let error = if status >= 400 {
+                            ServiceError::from_response(&response.headers, &body)
+                        } else {
+                            None
+                        };
+                        if status < 400 {
+                            self.record_parameters(&response, &body);
+                        }
+                        // hand the body on as though it was never read
+                        response.body = ByteStream::from(body);
+                        self.finish(Ok((&response, error.as_ref())));
+                        return Ok(Async::Ready(response));
+                    }
+                    Ok(Async::NotReady) => return Ok(Async::NotReady),
+                    Err(err) => {
+                        self.buffering = None;
+                        let err = HttpDispatchError::new(format!(
+                            "Failed to read response body: {}",
+                            err
+                        ));
+                        self.finish(Err(&err));
+                        return Err(err);
+                    }
+                }
+            }
+            let request = match self.request.as_mut() {
+                Some(request) => request,
+                None => return Err(HttpDispatchError::new("Request was refused".into())),
+            };
+            match request.poll() {
+                Ok(Async::Ready(res)) => {
+                    let failed = res.status.as_u16() >= 400;
+                    // error bodies of traced requests are read to record their errors, while
+                    // successful bodies are only read for the parameters of JSON protocol services
+                    let buffered = self.sampled()
+                        && (failed || (self.buffered && operation::is_json(&res.headers)));
+                    if buffered {
+                        self.buffering = Some((res, Vec::new()));
+                        continue;
+                    }
+                    if self.buffered && !failed {
+                        self.record_parameters(&res, &[]);
+                    }
+                    self.finish(Ok((&res, None)));
+                    return Ok(Async::Ready(res));
+                }
+                Ok(Async::NotReady) => return Ok(Async::NotReady),
+                Err(err) => {
+                    self.finish(Err(&err));
+                    return Err(err);
+                }
             }
-            err @ Err(_) => err,
-            other => other,
         }
     }
 }
+
+/// Record a response's status on a subsegment, marking client errors as errors,
+/// throttled requests as throttled errors and server errors as faults
+fn record_response(
+    subsegment: &mut Subsegment,
+    status: u16,
+    content_length: Option<i64>,
+    throttled: bool,
+) {
+    let http = subsegment.http.get_or_insert_with(Http::default);
+    http.response = Some(Response {
+        status: Some(status),
+        content_length,
+    });
+    match status {
+        _ if status == 429 || throttled => {
+            subsegment.error = true;
+            subsegment.throttled = true;
+        }
+        400..=499 => subsegment.error = true,
+        500..=599 => subsegment.fault = true,
+        _ => (),
+    }
+}
+
+fn content_length(headers: &HashMap<String, String>) -> Option<i64> {
+    headers
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+        .and_then(|(_, value)| value.parse().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::{
+        future::{self, FutureResult},
+        stream,
+    };
+    use rusoto_core::{credential::AwsCredentials, Region};
+    use serde_json::Value;
+    use std::{io, net::UdpSocket, sync::Mutex};
+    use xray::Segment;
+
+    const BODY: &str = r#"{"ConsumedCapacity":{"TableName":"users","CapacityUnits":0.5}}"#;
+
+    /// Dispatches requests to canned statuses and bodies in turn, repeating the last,
+    /// and keeps the last trace and authorization headers sent
+    struct MockDispatcher(
+        Mutex<Vec<Result<(u16, &'static str), String>>>,
+        Arc<Mutex<Option<String>>>,
+        Arc<Mutex<Option<String>>>,
+    );
+
+    impl MockDispatcher {
+        fn new(results: Vec<Result<(u16, &'static str), String>>) -> Self {
+            MockDispatcher(Mutex::new(results), Arc::default(), Arc::default())
+        }
+    }
+
+    fn header(
+        request: &SignedRequest,
+        name: &str,
+    ) -> Option<String> {
+        request
+            .headers
+            .get(name)
+            .and_then(|values| values.first())
+            .map(|value| String::from_utf8_lossy(value).to_string())
+    }
+
+    impl DispatchSignedRequest for MockDispatcher {
+        type Future = FutureResult<HttpResponse, HttpDispatchError>;
+        fn dispatch(
+            &self,
+            request: SignedRequest,
+            _: Option<Duration>,
+        ) -> Self::Future {
+            *self.1.lock().expect("poisoned lock") = header(&request, "x-amzn-trace-id");
+            *self.2.lock().expect("poisoned lock") = header(&request, "authorization");
+            let mut results = self.0.lock().expect("poisoned lock");
+            let result = if results.len() > 1 {
+                results.remove(0)
+            } else {
+                results[0].clone()
+            };
+            future::result(match result {
+                Ok((status, body)) => Ok(HttpResponse {
+                    status: http::StatusCode::from_u16(status).expect("invalid status"),
+                    body: ByteStream::from(body.as_bytes().to_vec()),
+                    headers: vec![
+                        ("content-length".to_string(), body.len().to_string()),
+                        (
+                            "content-type".to_string(),
+                            "application/x-amz-json-1.0".to_string(),
+                        ),
+                        ("x-amzn-RequestId".to_string(), "8476a536".to_string()),
+                    ]
+                    .into_iter()
+                    .collect(),
+                }),
+                Err(message) => Err(HttpDispatchError::new(message)),
+            })
+        }
+    }
+
+    fn get_item() -> SignedRequest {
+        let mut request = SignedRequest::new("POST", "dynamodb", &Region::UsEast1, "/");
+        request.add_header("X-Amz-Target", "DynamoDB_20120810.GetItem");
+        request.set_payload(Some(
+            br#"{"TableName":"users","ConsistentRead":true,"Key":{}}"#.to_vec(),
+        ));
+        request
+    }
+
+    /// Return a socket standing in for the daemon and a client sending to it
+    fn daemon() -> (UdpSocket, Arc<Client>) {
+        let daemon = UdpSocket::bind("127.0.0.1:0").expect("failed to bind socket");
+        daemon
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .expect("failed to set timeout");
+        let client = Client::new(daemon.local_addr().expect("failed to get address"))
+            .expect("failed to create client");
+        (daemon, Arc::new(client))
+    }
+
+    fn received(daemon: &UdpSocket) -> Option<Value> {
+        let mut buf = [0; 8192];
+        daemon.recv(&mut buf).ok().map(|len| {
+            let packet = String::from_utf8_lossy(&buf[..len]).to_string();
+            let (_, body) = packet.split_at(packet.find("\\n").expect("no header") + 2);
+            serde_json::from_str(body).expect("invalid document")
+        })
+    }
+
+    /// Dispatch a request within an optional active segment, returning the subsegment
+    /// sent and the trace header the request carried
+    fn dispatched(
+        result: Result<u16, String>,
+        parent: Option<Segment>,
+    ) -> (Option<Value>, Option<String>) {
+        let (daemon, client) = daemon();
+        let dispatcher = MockDispatcher::new(vec![result.map(|status| (status, BODY))]);
+        let trace_header = dispatcher.1.clone();
+        let requests = TracedRequests::new_with_client(dispatcher, client);
+        let entered = parent.map(context::enter);
+        let _ = requests.dispatch(get_item(), None).wait();
+        drop(entered);
+        let subsegment = received(&daemon);
+        let trace_header = trace_header.lock().expect("poisoned lock").take();
+        (subsegment, trace_header)
+    }
+
+    fn traced(result: Result<u16, String>) -> Value {
+        dispatched(result, Some(Segment::begin("caller")))
+            .0
+            .expect("no subsegment sent")
+    }
+
+    fn traced_error(body: &'static str) -> Value {
+        attempted(vec![Ok((400, body))], 1).1.remove(0)
+    }
+
+    /// Dispatch a request `attempts` times within an active segment, returning the
+    /// responses' statuses and the subsegments sent
+    fn attempted(
+        results: Vec<Result<(u16, &'static str), String>>,
+        attempts: usize,
+    ) -> (Vec<Option<u16>>, Vec<Value>) {
+        let (daemon, client) = daemon();
+        let requests = TracedRequests::new_with_client(MockDispatcher::new(results), client);
+        let entered = context::enter(Segment::begin("caller"));
+        let statuses = (0..attempts)
+            .map(|_| {
+                requests
+                    .dispatch(get_item(), None)
+                    .wait()
+                    .ok()
+                    .map(|response| response.status.as_u16())
+            })
+            .collect();
+        drop(entered);
+        let subsegments = (0..attempts)
+            .map(|_| received(&daemon).expect("no subsegment sent"))
+            .collect();
+        (statuses, subsegments)
+    }
+
+    #[test]
+    fn subsegments_belong_to_the_active_segment() {
+        let segment = Segment::begin("caller");
+        let (trace_id, id) = (segment.trace_id().to_string(), segment.id().to_string());
+        let (subsegment, trace_header) = dispatched(Ok(200), Some(segment));
+        let subsegment = subsegment.expect("no subsegment sent");
+        assert_eq!(subsegment["trace_id"], trace_id);
+        assert_eq!(subsegment["parent_id"], id);
+        assert_eq!(subsegment["namespace"], "aws");
+        assert_eq!(subsegment["aws"]["region"], "us-east-1");
+        assert_eq!(subsegment["aws"]["request_id"], "8476a536");
+        assert_eq!(
+            trace_header,
+            Some(format!(
+                "Root={};Parent={};Sampled=1",
+                trace_id,
+                subsegment["id"].as_str().expect("no id")
+            ))
+        );
+    }
+
+    #[test]
+    fn trace_headers_leave_signatures_intact() {
+        let (_daemon, client) = daemon();
+        let dispatcher = MockDispatcher::new(vec![Ok((200, BODY))]);
+        let (trace_header, authorization) = (dispatcher.1.clone(), dispatcher.2.clone());
+        let requests = TracedRequests::new_with_client(dispatcher, client);
+        let mut request = get_item();
+        request.sign(&AwsCredentials::new(
+            "AKIDEXAMPLE",
+            "wJalrXUtnFEMI/K7MDENG+bPxRfiCYEXAMPLEKEY",
+            None,
+            None,
+        ));
+        let signed = header(&request, "authorization").expect("request not signed");
+        let entered = context::enter(Segment::begin("caller"));
+        let _ = requests.dispatch(request, None).wait();
+        drop(entered);
+        assert!(trace_header.lock().expect("poisoned lock").is_some());
+        let authorization = authorization
+            .lock()
+            .expect("poisoned lock")
+            .take()
+            .expect("no authorization header");
+        assert_eq!(authorization, signed);
+        let signed_headers = authorization
+            .split(", ")
+            .find(|part| part.starts_with("SignedHeaders="))
+            .expect("no signed headers");
+        assert!(signed_headers.contains("x-amz-target"));
+        assert!(!signed_headers.contains("x-amzn-trace-id"));
+    }
+
+    #[test]
+    fn scoped_requests_are_traced_on_thread_pools() {
+        let (daemon, client) = daemon();
+        let requests =
+            TracedRequests::new_with_client(MockDispatcher::new(vec![Ok((200, BODY))]), client);
+        let segment = Segment::begin("caller");
+        let id = segment.id().to_string();
+        let mut runtime = tokio::runtime::Runtime::new().expect("failed to initialize runtime");
+        let (_, document) = runtime
+            .block_on(scoped(
+                segment,
+                futures::future::lazy(move || requests.dispatch(get_item(), None)),
+            ))
+            .map_err(|(err, _)| err)
+            .expect("request failed");
+        assert_eq!(
+            document.map(|document| document.id().to_string()),
+            Some(id.clone())
+        );
+        let subsegment = received(&daemon).expect("no subsegment sent");
+        assert_eq!(subsegment["parent_id"], id);
+    }
+
+    #[test]
+    fn requests_without_context_are_not_traced() {
+        assert_eq!(dispatched(Ok(200), None), (None, None));
+    }
+
+    #[test]
+    fn unsampled_requests_are_not_traced() {
+        let header = "Root=1-5759e988-bd862e3fe1be46a994272793;Sampled=0"
+            .parse::<Header>()
+            .expect("invalid header");
+        let (subsegment, trace_header) =
+            dispatched(Ok(200), Some(Segment::from_header("caller", &header)));
+        assert!(subsegment.is_none());
+        assert!(trace_header
+            .expect("no trace header")
+            .ends_with(";Sampled=0"));
+    }
+
+    #[test]
+    fn successful_responses_are_recorded() {
+        let subsegment = traced(Ok(200));
+        assert_eq!(subsegment["name"], "dynamodb");
+        assert!(subsegment["end_time"].is_number());
+        assert_eq!(subsegment["http"]["response"]["status"], 200);
+        assert_eq!(subsegment["http"]["response"]["content_length"], BODY.len());
+        assert!(subsegment["error"].is_null() && subsegment["fault"].is_null());
+    }
+
+    #[test]
+    fn manifest_parameters_are_recorded() {
+        let subsegment = traced(Ok(200));
+        assert_eq!(subsegment["aws"]["operation"], "GetItem");
+        assert_eq!(subsegment["aws"]["table_name"], "users");
+        assert_eq!(subsegment["aws"]["consistent_read"], true);
+        assert_eq!(subsegment["aws"]["consumed_capacity"]["CapacityUnits"], 0.5);
+    }
+
+    #[test]
+    fn read_responses_are_returned_whole() {
+        let (_daemon, client) = daemon();
+        let requests =
+            TracedRequests::new_with_client(MockDispatcher::new(vec![Ok((200, BODY))]), client);
+        let entered = context::enter(Segment::begin("caller"));
+        let response = requests
+            .dispatch(get_item(), None)
+            .wait()
+            .expect("request failed");
+        drop(entered);
+        let body = response.body.concat2().wait().expect("failed to read body");
+        assert_eq!(&body[..], BODY.as_bytes());
+    }
+
+    /// Dispatches Lambda invocations to responses whose payloads fail to be read
+    struct UnreadablePayloads;
+
+    impl DispatchSignedRequest for UnreadablePayloads {
+        type Future = FutureResult<HttpResponse, HttpDispatchError>;
+        fn dispatch(
+            &self,
+            _: SignedRequest,
+            _: Option<Duration>,
+        ) -> Self::Future {
+            future::ok(HttpResponse {
+                status: http::StatusCode::OK,
+                body: ByteStream::new(stream::once(Err(io::Error::new(
+                    io::ErrorKind::Other,
+                    "payload read",
+                )))),
+                headers: vec![
+                    ("content-type".to_string(), "application/json".to_string()),
+                    ("x-amz-function-error".to_string(), "Unhandled".to_string()),
+                ]
+                .into_iter()
+                .collect(),
+            })
+        }
+    }
+
+    #[test]
+    fn rest_responses_are_returned_unread() {
+        let (daemon, client) = daemon();
+        let requests = TracedRequests::new_with_client(UnreadablePayloads, client);
+        let request = SignedRequest::new(
+            "POST",
+            "lambda",
+            &Region::UsEast1,
+            "/2015-03-31/functions/handler/invocations",
+        );
+        let entered = context::enter(Segment::begin("caller"));
+        let response = requests
+            .dispatch(request, None)
+            .wait()
+            .expect("request failed");
+        drop(entered);
+        assert!(response.body.concat2().wait().is_err());
+        let subsegment = received(&daemon).expect("no subsegment sent");
+        assert_eq!(subsegment["aws"]["operation"], "Invoke");
+        assert_eq!(subsegment["aws"]["function_error"], "Unhandled");
+        assert_eq!(subsegment["aws"]["status_code"], 200);
+    }
+
+    #[test]
+    fn custom_manifests_replace_the_default() {
+        let (daemon, client) = daemon();
+        let manifest = r#"{"services":{"dynamodb":{"operations":{"GetItem":{"request_parameters":["Key"]}}}}}"#
+            .parse()
+            .expect("invalid manifest");
+        let requests =
+            TracedRequests::new_with_client(MockDispatcher::new(vec![Ok((200, BODY))]), client)
+                .with_manifest(manifest);
+        let entered = context::enter(Segment::begin("caller"));
+        let _ = requests.dispatch(get_item(), None).wait();
+        drop(entered);
+        let subsegment = received(&daemon).expect("no subsegment sent");
+        assert_eq!(subsegment["aws"]["key"], serde_json::json!({}));
+        assert!(subsegment["aws"]["consistent_read"].is_null());
+        assert!(subsegment["aws"]["consumed_capacity"].is_null());
+    }
+
+    #[test]
+    fn throttled_responses_are_errors() {
+        let subsegment = traced(Ok(429));
+        assert_eq!(subsegment["error"], true);
+        assert_eq!(subsegment["throttle"], true);
+    }
+
+    #[test]
+    fn server_errors_are_faults() {
+        assert_eq!(traced(Ok(503))["fault"], true);
+    }
+
+    #[test]
+    fn service_errors_are_recorded_as_remote_exceptions() {
+        let subsegment = traced_error(
+            r#"{"__type":"com.amazonaws.dynamodb.v20120810#ResourceNotFoundException","message":"Requested resource not found"}"#,
+        );
+        assert_eq!(subsegment["error"], true);
+        assert!(subsegment["throttle"].is_null());
+        let exception = &subsegment["cause"]["exceptions"][0];
+        assert_eq!(exception["type"], "ResourceNotFoundException");
+        assert_eq!(exception["message"], "Requested resource not found");
+        assert_eq!(exception["remote"], true);
+    }
+
+    #[test]
+    fn throttling_errors_are_throttled() {
+        let subsegment = traced_error(
+            r#"{"__type":"com.amazonaws.dynamodb.v20120810#ProvisionedThroughputExceededException","message":"Rate exceeded"}"#,
+        );
+        assert_eq!(subsegment["error"], true);
+        assert_eq!(subsegment["throttle"], true);
+        assert_eq!(
+            subsegment["cause"]["exceptions"][0]["type"],
+            "ProvisionedThroughputExceededException"
+        );
+    }
+
+    #[test]
+    fn each_attempt_is_recorded() {
+        let (statuses, subsegments) = attempted(
+            vec![
+                Err("connection reset".into()),
+                Ok((503, "")),
+                Ok((200, BODY)),
+            ],
+            3,
+        );
+        assert_eq!(statuses, vec![None, Some(503), Some(200)]);
+        assert_eq!(subsegments[0]["fault"], true);
+        assert_eq!(subsegments[1]["fault"], true);
+        assert_eq!(subsegments[1]["http"]["response"]["status"], 503);
+        assert_eq!(subsegments[2]["http"]["response"]["status"], 200);
+        assert_eq!(subsegments[0]["parent_id"], subsegments[2]["parent_id"]);
+        assert_ne!(subsegments[0]["id"], subsegments[2]["id"]);
+        assert!(subsegments[0]["precursor_ids"].is_null());
+        assert_eq!(
+            subsegments[2]["precursor_ids"],
+            serde_json::json!([subsegments[0]["id"], subsegments[1]["id"]])
+        );
+    }
+
+    #[test]
+    fn failed_requests_are_not_dispatched_again() {
+        let (statuses, subsegments) = attempted(vec![Ok((503, "")), Ok((200, BODY))], 1);
+        assert_eq!(statuses, vec![Some(503)]);
+        assert_eq!(subsegments[0]["fault"], true);
+    }
+
+    #[test]
+    fn recursive_requests_are_refused() {
+        let (daemon, client) = daemon();
+        let dispatcher = MockDispatcher::new(vec![Ok((200, BODY))]);
+        let trace_header = dispatcher.1.clone();
+        let requests = TracedRequests::new_with_client(dispatcher, client)
+            .with_recursion_guard(RecursionGuard::new().max_depth(2));
+        let header = "Root=1-5759e988-bd862e3fe1be46a994272793;Sampled=1;Lineage=a87bd80c:3"
+            .parse::<Header>()
+            .expect("invalid header");
+        let entered = context::enter(Segment::from_header("caller", &header));
+        let result = requests.dispatch(get_item(), None).wait();
+        drop(entered);
+        assert!(result.is_err());
+        assert!(trace_header.lock().expect("poisoned lock").is_none());
+        let subsegment = received(&daemon).expect("no subsegment sent");
+        assert_eq!(subsegment["fault"], true);
+        assert_eq!(
+            subsegment["cause"]["exceptions"][0]["message"],
+            "Lineage depth 3 exceeds the maximum of 2"
+        );
+    }
+
+    #[test]
+    fn dispatch_errors_are_recorded_as_causes() {
+        let subsegment = traced(Err("connection reset".into()));
+        assert_eq!(subsegment["fault"], true);
+        assert_eq!(
+            subsegment["cause"]["exceptions"][0]["message"],
+            "connection reset"
+        );
+    }
+}
@@ -0,0 +1,317 @@
+//! Manifests choosing which parameters of AWS operations are recorded
+//!
+//! Manifests share the JSON format of the service handler manifests of the official
+//! X-Ray SDKs, so those may be loaded unchanged
+//!
+//! ```json
+//! {
+//!   "services": {
+//!     "dynamodb": {
+//!       "operations": {
+//!         "GetItem": {
+//!           "request_parameters": ["ConsistentRead"],
+//!           "response_parameters": ["ConsumedCapacity"]
+//!         }
+//!       }
+//!     }
+//!   }
+//! }
+//! ```
+
+use crate::operation::Parameters;
+use serde_derive::Deserialize;
+use serde_json::Value;
+use std::{collections::HashMap, io::Read, str::FromStr};
+use xray::AwsOperation;
+
+/// The built-in manifest
+const DEFAULT_MANIFEST: &str = include_str!("manifest.json");
+
+/// Parameters which have their own `AwsOperation` fields
+const FIELDS: &[&str] = &[
+    "operation",
+    "account_id",
+    "region",
+    "request_id",
+    "queue_url",
+    "table_name",
+];
+
+/// Request and response parameters to record for the operations of each AWS service
+///
+/// The default manifest records parameters such as DynamoDB's `ConsistentRead` and
+/// `ConsumedCapacity`, S3 bucket names and keys, SNS topic ARNs and Lambda function names
+#[derive(Debug, Clone, Deserialize)]
+pub struct Manifest {
+    #[serde(default)]
+    services: HashMap<String, Service>,
+}
+
+#[derive(Debug, Clone, Default, Deserialize)]
+struct Service {
+    #[serde(default)]
+    operations: HashMap<String, Operation>,
+}
+
+/// Parameters to record for a single operation
+#[derive(Debug, Clone, Default, Deserialize)]
+pub(crate) struct Operation {
+    #[serde(default)]
+    request_parameters: Vec<String>,
+    #[serde(default)]
+    request_descriptors: HashMap<String, Descriptor>,
+    #[serde(default)]
+    response_parameters: Vec<String>,
+    #[serde(default)]
+    response_descriptors: HashMap<String, Descriptor>,
+}
+
+/// How to record a parameter other than by its value
+#[derive(Debug, Clone, Default, Deserialize)]
+struct Descriptor {
+    /// The parameter is a list
+    #[serde(default)]
+    list: bool,
+    /// The parameter is a map
+    #[serde(default)]
+    map: bool,
+    /// Record the number of entries of a list or map
+    #[serde(default)]
+    get_count: bool,
+    /// Record the keys of a map
+    #[serde(default)]
+    get_keys: bool,
+    /// Record under this name
+    rename_to: Option<String>,
+}
+
+impl Default for Manifest {
+    fn default() -> Self {
+        DEFAULT_MANIFEST.parse().expect("invalid built-in manifest")
+    }
+}
+
+impl FromStr for Manifest {
+    type Err = serde_json::Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        serde_json::from_str(s)
+    }
+}
+
+impl Manifest {
+    /// Read a custom manifest, for example from a file
+    pub fn from_reader<R>(reader: R) -> Result<Self, serde_json::Error>
+    where
+        R: Read,
+    {
+        serde_json::from_reader(reader)
+    }
+
+    /// Return the parameters to record for an operation of a service
+    pub(crate) fn operation(
+        &self,
+        service: &str,
+        operation: &str,
+    ) -> Option<&Operation> {
+        self.services
+            .iter()
+            .find(|(name, _)| name.eq_ignore_ascii_case(service))
+            .and_then(|(_, service)| service.operations.get(operation))
+    }
+}
+
+impl Operation {
+    /// Return true if any response parameters are recorded
+    pub(crate) fn records_response(&self) -> bool {
+        !self.response_parameters.is_empty() || !self.response_descriptors.is_empty()
+    }
+
+    /// Record request parameters
+    pub(crate) fn record_request(
+        &self,
+        parameters: &Parameters,
+        aws: &mut AwsOperation,
+    ) {
+        record(
+            &self.request_parameters,
+            &self.request_descriptors,
+            parameters,
+            aws,
+        )
+    }
+
+    /// Record response parameters
+    pub(crate) fn record_response(
+        &self,
+        parameters: &Parameters,
+        aws: &mut AwsOperation,
+    ) {
+        record(
+            &self.response_parameters,
+            &self.response_descriptors,
+            parameters,
+            aws,
+        )
+    }
+}
+
+fn record(
+    names: &[String],
+    descriptors: &HashMap<String, Descriptor>,
+    parameters: &Parameters,
+    aws: &mut AwsOperation,
+) {
+    let values = names
+        .iter()
+        .filter_map(|name| Some((snake_case(name), parameters.get(name)?)))
+        .chain(descriptors.iter().filter_map(|(name, descriptor)| {
+            let key = descriptor
+                .rename_to
+                .clone()
+                .unwrap_or_else(|| snake_case(name));
+            Some((key, descriptor.describe(parameters.get(name)?)?))
+        }));
+    for (key, value) in values {
+        if !FIELDS.contains(&key.as_str()) {
+            aws.parameters.insert(key, value);
+        }
+    }
+}
+
+impl Descriptor {
+    /// Return the value to record for a parameter, if it has the described type
+    fn describe(
+        &self,
+        value: Value,
+    ) -> Option<Value> {
+        match value {
+            Value::Array(items) if self.list && self.get_count => Some(items.len().into()),
+            Value::Object(entries) if self.map && self.get_count => Some(entries.len().into()),
+            Value::Object(entries) if self.map && self.get_keys => Some(Value::Array(
+                entries
+                    .into_iter()
+                    .map(|(key, _)| Value::String(key))
+                    .collect(),
+            )),
+            value if !self.list && !self.map => Some(value),
+            _ => None,
+        }
+    }
+}
+
+/// Return the name a parameter is recorded under, for example `consistent_read` for `ConsistentRead`
+fn snake_case(name: &str) -> String {
+    let chars = name.chars().collect::<Vec<_>>();
+    let mut snake = String::with_capacity(name.len() + 4);
+    for (i, c) in chars.iter().enumerate() {
+        if i > 0 && c.is_uppercase() {
+            let previous = chars[i - 1];
+            // acronyms end before the last capital of the next word, as in `SSEKMSKeyId`
+            let word = chars.get(i + 1).map_or(false, |next| next.is_lowercase());
+            if !previous.is_uppercase() || word {
+                snake.push('_');
+            }
+        }
+        snake.extend(c.to_lowercase());
+    }
+    snake
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn recorded(
+        operation: &Operation,
+        request: Value,
+        response: Value,
+    ) -> Value {
+        let mut aws = AwsOperation::default();
+        operation.record_request(&Parameters::Json(request), &mut aws);
+        operation.record_response(&Parameters::Json(response), &mut aws);
+        serde_json::to_value(&aws).expect("failed to serialize")
+    }
+
+    #[test]
+    fn default_manifest_records_dynamodb_parameters() {
+        let manifest = Manifest::default();
+        let get_item = manifest
+            .operation("dynamodb", "GetItem")
+            .expect("no GetItem parameters");
+        assert!(get_item.records_response());
+        assert_eq!(
+            recorded(
+                get_item,
+                json!({
+                    "TableName": "users",
+                    "ConsistentRead": true,
+                    "ProjectionExpression": "id",
+                    "Key": {}
+                }),
+                json!({
+                    "ConsumedCapacity": { "CapacityUnits": 1.0 },
+                    "Item": {}
+                })
+            ),
+            json!({
+                "consistent_read": true,
+                "projection_expression": "id",
+                "consumed_capacity": { "CapacityUnits": 1.0 }
+            })
+        );
+        assert!(manifest.operation("DynamoDB", "ListTables").is_some());
+        assert!(manifest.operation("dynamodb", "DescribeTable").is_none());
+    }
+
+    #[test]
+    fn descriptors_record_counts_keys_and_renames() {
+        let manifest = r#"{
+            "services": {
+                "test": {
+                    "operations": {
+                        "Test": {
+                            "request_parameters": ["TableName"],
+                            "request_descriptors": {
+                                "Items": { "map": true, "get_keys": true, "rename_to": "names" },
+                                "Bucket": { "rename_to": "bucket_name" },
+                                "Wrong": { "list": true, "get_count": true }
+                            },
+                            "response_descriptors": {
+                                "Messages": { "list": true, "get_count": true }
+                            }
+                        }
+                    }
+                }
+            }
+        }"#
+        .parse::<Manifest>()
+        .expect("invalid manifest");
+        let operation = manifest.operation("test", "Test").expect("no operation");
+        assert_eq!(
+            recorded(
+                operation,
+                json!({
+                    "TableName": "users",
+                    "Items": { "a": 1 },
+                    "Bucket": "bucket",
+                    "Wrong": "value"
+                }),
+                json!({ "Messages": [1, 2, 3] })
+            ),
+            json!({
+                "names": ["a"],
+                "bucket_name": "bucket",
+                "messages": 3
+            })
+        );
+    }
+
+    #[test]
+    fn names_are_snake_cased() {
+        assert_eq!(snake_case("ConsistentRead"), "consistent_read");
+        assert_eq!(snake_case("TopicArn"), "topic_arn");
+        assert_eq!(snake_case("ListObjectsV2"), "list_objects_v2");
+        assert_eq!(snake_case("SSEKMSKeyId"), "ssekms_key_id");
+    }
+}
@@ -0,0 +1,329 @@
+//! Describes AWS operations from the requests Rusoto dispatches
+
+use rusoto_core::{
+    request::HttpResponse,
+    signature::{SignedRequest, SignedRequestPayload},
+};
+use serde_json::Value;
+use std::collections::HashMap;
+use url::form_urlencoded;
+use xray::AwsOperation;
+
+/// Response headers AWS services return request ids in
+const REQUEST_ID_HEADERS: &[&str] = &["x-amzn-requestid", "x-amz-request-id"];
+
+/// Headers REST protocol services carry parameters in, and the parameters they carry
+const HEADER_PARAMETERS: &[(&str, &str)] = &[
+    ("x-amz-invocation-type", "InvocationType"),
+    ("x-amz-log-type", "LogType"),
+    ("x-amz-function-error", "FunctionError"),
+];
+
+/// The parameters of an AWS request or response
+///
+/// JSON protocol services send parameters as a JSON document. Query protocol
+/// services send form encoded `Action` parameters, while REST protocol services
+/// send them in paths, query strings and headers
+pub(crate) enum Parameters {
+    Json(Value),
+    Form(Vec<(String, Value)>),
+}
+
+impl Parameters {
+    /// Return the parameters of a request
+    pub(crate) fn from_request(request: &SignedRequest) -> Self {
+        let body = match &request.payload {
+            Some(SignedRequestPayload::Buffer(body)) => &body[..],
+            _ => &[][..],
+        };
+        if request_header(request, "x-amz-target").is_some() {
+            return Parameters::from_json(body);
+        }
+        let mut params = request
+            .params
+            .iter()
+            .filter_map(|(key, value)| value.clone().map(|value| (key.clone(), value)))
+            .collect::<Vec<_>>();
+        if request_header(request, "content-type").map_or(false, |value| {
+            value.starts_with("application/x-www-form-urlencoded")
+        }) {
+            params.extend(form_urlencoded::parse(body).into_owned());
+        }
+        params.extend(path_parameters(&request.service, &request.path));
+        params.extend(HEADER_PARAMETERS.iter().filter_map(|(name, param)| {
+            request_header(request, name).map(|value| (param.to_string(), value))
+        }));
+        Parameters::Form(
+            params
+                .into_iter()
+                .map(|(key, value)| (key, Value::String(value)))
+                .collect(),
+        )
+    }
+
+    /// Return the parameters of a response from its buffered body
+    ///
+    /// Only JSON protocol responses have their bodies read, as REST protocol services such
+    /// as Lambda return payloads which aren't AWS's own. Their parameters are read from
+    /// headers, and `body` may be left empty
+    pub(crate) fn from_response(
+        response: &HttpResponse,
+        body: &[u8],
+    ) -> Self {
+        if is_json(&response.headers) {
+            return Parameters::from_json(body);
+        }
+        Parameters::Form(
+            HEADER_PARAMETERS
+                .iter()
+                .filter_map(|(name, param)| {
+                    response_header(&response.headers, name)
+                        .map(|value| (param.to_string(), Value::String(value.clone())))
+                })
+                .chain(Some((
+                    "StatusCode".to_string(),
+                    Value::from(response.status.as_u16()),
+                )))
+                .collect(),
+        )
+    }
+
+    fn from_json(body: &[u8]) -> Self {
+        match serde_json::from_slice(body) {
+            Ok(value @ Value::Object(_)) => Parameters::Json(value),
+            _ => Parameters::Form(Vec::new()),
+        }
+    }
+
+    /// Return the value of a top level parameter
+    pub(crate) fn get(
+        &self,
+        name: &str,
+    ) -> Option<Value> {
+        match self {
+            Parameters::Json(value) => value.get(name).cloned(),
+            Parameters::Form(params) => params
+                .iter()
+                .find(|(key, _)| key == name)
+                .map(|(_, value)| value.clone()),
+        }
+    }
+
+    /// Return the value of a top level string parameter
+    pub(crate) fn get_str(
+        &self,
+        name: &str,
+    ) -> Option<String> {
+        match self.get(name) {
+            Some(Value::String(value)) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+/// Describe the operation a request invokes
+pub(crate) fn operation(
+    request: &SignedRequest,
+    parameters: &Parameters,
+) -> AwsOperation {
+    AwsOperation {
+        operation: target(request)
+            .or_else(|| parameters.get_str("Action"))
+            .or_else(|| rest_operation(request)),
+        region: Some(request.region.name().to_string()),
+        table_name: parameters.get_str("TableName"),
+        queue_url: parameters.get_str("QueueUrl"),
+        ..AwsOperation::default()
+    }
+}
+
+/// Return true if a response carries a JSON protocol body, which is read to record
+/// response parameters
+pub(crate) fn is_json(headers: &HashMap<String, String>) -> bool {
+    response_header(headers, "content-type")
+        .map_or(false, |value| value.starts_with("application/x-amz-json"))
+}
+
+/// Return the id AWS assigned a request from its response headers
+pub(crate) fn request_id(headers: &HashMap<String, String>) -> Option<String> {
+    REQUEST_ID_HEADERS
+        .iter()
+        .find_map(|name| response_header(headers, name))
+        .cloned()
+}
+
+/// Return the operation named by a JSON protocol request's `X-Amz-Target` header,
+/// for example `GetItem` from `DynamoDB_20120810.GetItem`
+fn target(request: &SignedRequest) -> Option<String> {
+    let target = request_header(request, "x-amz-target")?;
+    target
+        .rsplit('.')
+        .next()
+        .filter(|operation| !operation.is_empty())
+        .map(str::to_string)
+}
+
+/// Return the operation a REST protocol request invokes from its method and path
+///
+/// Only the S3 object and Lambda invocation operations are recognized. Requests for
+/// subresources, such as an object's `?acl`, are left undescribed
+fn rest_operation(request: &SignedRequest) -> Option<String> {
+    if request.params.values().any(Option::is_none) {
+        return None;
+    }
+    let segments = request
+        .path
+        .split('/')
+        .filter(|segment| !segment.is_empty())
+        .collect::<Vec<_>>();
+    let operation = match (
+        request.service.as_str(),
+        request.method.as_str(),
+        segments.len(),
+    ) {
+        ("s3", "GET", 1) if request.params.contains_key("list-type") => "ListObjectsV2",
+        ("s3", "GET", 1) => "ListObjects",
+        ("s3", "GET", n) if n > 1 => "GetObject",
+        ("s3", "HEAD", n) if n > 1 => "HeadObject",
+        ("s3", "PUT", n) if n > 1 && request_header(request, "x-amz-copy-source").is_some() => {
+            "CopyObject"
+        }
+        ("s3", "PUT", n) if n > 1 => "PutObject",
+        ("s3", "DELETE", n) if n > 1 => "DeleteObject",
+        ("lambda", "POST", 4) if segments[1] == "functions" && segments[3] == "invocations" => {
+            "Invoke"
+        }
+        ("lambda", "POST", 4) if segments[1] == "functions" && segments[3] == "invoke-async" => {
+            "InvokeAsync"
+        }
+        _ => return None,
+    };
+    Some(operation.into())
+}
+
+/// Return the parameters REST protocol services carry in request paths
+fn path_parameters(
+    service: &str,
+    path: &str,
+) -> Vec<(String, String)> {
+    let path = path.trim_start_matches('/');
+    match service {
+        "s3" => ["Bucket", "Key"]
+            .iter()
+            .zip(path.splitn(2, '/'))
+            .filter(|(_, value)| !value.is_empty())
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect(),
+        "lambda" => path
+            .split('/')
+            .skip_while(|segment| *segment != "functions")
+            .nth(1)
+            .map(|function| ("FunctionName".to_string(), function.to_string()))
+            .into_iter()
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Return the first value of a request header, whose names Rusoto keeps in lowercase
+fn request_header(
+    request: &SignedRequest,
+    name: &str,
+) -> Option<String> {
+    request
+        .headers
+        .get(name)
+        .and_then(|values| values.first())
+        .map(|value| String::from_utf8_lossy(value).to_string())
+}
+
+/// Return the value of a response header, whose names are matched case insensitively
+fn response_header<'a>(
+    headers: &'a HashMap<String, String>,
+    name: &str,
+) -> Option<&'a String> {
+    headers
+        .iter()
+        .find(|(header, _)| header.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rusoto_core::Region;
+
+    #[test]
+    fn json_operations_are_described() {
+        let mut request = SignedRequest::new("POST", "dynamodb", &Region::UsWest2, "/");
+        request.add_header("X-Amz-Target", "DynamoDB_20120810.GetItem");
+        request.set_payload(Some(br#"{"TableName":"users","Key":{}}"#.to_vec()));
+        let operation = operation(&request, &Parameters::from_request(&request));
+        assert_eq!(operation.operation, Some("GetItem".into()));
+        assert_eq!(operation.region, Some("us-west-2".into()));
+        assert_eq!(operation.table_name, Some("users".into()));
+        assert!(operation.queue_url.is_none());
+    }
+
+    #[test]
+    fn query_operations_are_described() {
+        let mut request = SignedRequest::new("POST", "sqs", &Region::UsEast1, "/");
+        request.add_header("Content-Type", "application/x-www-form-urlencoded");
+        request.set_payload(Some(
+            b"Action=SendMessage&QueueUrl=https%3A%2F%2Fsqs.us-east-1.amazonaws.com%2F123456789012%2Fqueue&MessageBody=hi"
+                .to_vec(),
+        ));
+        let operation = operation(&request, &Parameters::from_request(&request));
+        assert_eq!(operation.operation, Some("SendMessage".into()));
+        assert_eq!(
+            operation.queue_url,
+            Some("https://sqs.us-east-1.amazonaws.com/123456789012/queue".into())
+        );
+    }
+
+    #[test]
+    fn rest_operations_are_described() {
+        let request = SignedRequest::new("GET", "s3", &Region::UsEast1, "/bucket/path/to/key");
+        let parameters = Parameters::from_request(&request);
+        assert_eq!(
+            operation(&request, &parameters).operation,
+            Some("GetObject".into())
+        );
+        assert_eq!(parameters.get_str("Bucket"), Some("bucket".into()));
+        assert_eq!(parameters.get_str("Key"), Some("path/to/key".into()));
+
+        let mut request = SignedRequest::new("GET", "s3", &Region::UsEast1, "/bucket/key");
+        request.params.insert("acl".into(), None);
+        assert!(operation(&request, &Parameters::from_request(&request))
+            .operation
+            .is_none());
+
+        let mut request = SignedRequest::new(
+            "POST",
+            "lambda",
+            &Region::UsEast1,
+            "/2015-03-31/functions/handler/invocations",
+        );
+        request.add_header("X-Amz-Invocation-Type", "Event");
+        request.set_payload(Some(br#"{"FunctionName":"event"}"#.to_vec()));
+        let parameters = Parameters::from_request(&request);
+        assert_eq!(
+            operation(&request, &parameters).operation,
+            Some("Invoke".into())
+        );
+        assert_eq!(parameters.get_str("FunctionName"), Some("handler".into()));
+        assert_eq!(parameters.get_str("InvocationType"), Some("Event".into()));
+    }
+
+    #[test]
+    fn request_ids_are_read_from_either_header() {
+        let headers = vec![("x-amz-request-id".to_string(), "abc".to_string())]
+            .into_iter()
+            .collect();
+        assert_eq!(request_id(&headers), Some("abc".into()));
+        let headers = vec![("x-amzn-RequestId".to_string(), "def".to_string())]
+            .into_iter()
+            .collect();
+        assert_eq!(request_id(&headers), Some("def".into()));
+    }
+}
@@ -0,0 +1,79 @@
+//! Makes a document active while futures run on thread pools
+
+use futures::{Async, Future, IntoFuture, Poll};
+use xray::context::{self, Active};
+
+/// A future which makes a segment or subsegment active on whichever thread polls it
+///
+/// Requests dispatched while the future is polled are traced as subsegments of the
+/// document. The document is handed back with the future's outcome, or as `None` if
+/// the panic hook flushed it
+pub struct Scoped<F> {
+    future: F,
+    document: Option<Active>,
+}
+
+/// Run `future` with `document` active each time it is polled
+pub fn scoped<D, F>(
+    document: D,
+    future: F,
+) -> Scoped<F::Future>
+where
+    D: Into<Active>,
+    F: IntoFuture,
+{
+    Scoped {
+        future: future.into_future(),
+        document: Some(document.into()),
+    }
+}
+
+impl<F> Future for Scoped<F>
+where
+    F: Future,
+{
+    type Item = (F::Item, Option<Active>);
+    type Error = (F::Error, Option<Active>);
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let future = &mut self.future;
+        match context::scope(&mut self.document, || future.poll()) {
+            Ok(Async::Ready(item)) => Ok(Async::Ready((item, self.document.take()))),
+            Ok(Async::NotReady) => Ok(Async::NotReady),
+            Err(err) => Err((err, self.document.take())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future;
+    use std::thread;
+    use tokio::runtime::Runtime;
+    use xray::Segment;
+
+    #[test]
+    fn documents_are_active_on_pool_threads() {
+        let segment = Segment::begin("task");
+        let segment_id = segment.id().clone();
+        let mut runtime = Runtime::new().expect("failed to initialize runtime");
+        let (active, document) = runtime
+            .block_on(scoped(
+                segment,
+                future::lazy(|| {
+                    future::ok::<_, ()>((
+                        thread::current().id(),
+                        context::with_current(|active| active.id().clone()),
+                    ))
+                }),
+            ))
+            .expect("future failed");
+        assert_ne!(active.0, thread::current().id());
+        assert_eq!(active.1, Some(segment_id.clone()));
+        assert!(context::with_current(|_| ()).is_none());
+        assert_eq!(
+            document.as_ref().map(|document| document.id().clone()),
+            Some(segment_id)
+        );
+    }
+}
@@ -1,4 +1,4 @@
-// run cargo +nightly bench
+// run cargo +nightly bench --features unstable
 
 #![feature(test)]
 extern crate test;
@@ -0,0 +1,41 @@
+// run cargo +nightly bench --features unstable
+
+#![feature(test)]
+extern crate test;
+
+use test::Bencher;
+use xray::{Client, Header, Segment};
+
+fn trace(
+    header: &Header,
+    client: &Client,
+) {
+    let mut segment = Segment::from_header("service", header);
+    let mut subsegment = segment.begin_subsegment("downstream");
+    subsegment.annotate("key", "value");
+    let _ = subsegment.put_metadata("default", "key", &[1, 2, 3]);
+    segment.end_subsegment(subsegment);
+    segment.end();
+    let _ = client.send_document(&segment);
+}
+
+fn bench_trace(
+    b: &mut Bencher,
+    sampled: &str,
+) {
+    let header = format!("Root=1-5759e988-bd862e3fe1be46a994272793;{}", sampled)
+        .parse::<Header>()
+        .expect("invalid header");
+    let client = Client::new(([127, 0, 0, 1], 2000).into()).expect("failed to create client");
+    b.iter(|| trace(&header, &client))
+}
+
+#[bench]
+fn bench_sampled_trace(b: &mut Bencher) {
+    bench_trace(b, "Sampled=1")
+}
+
+#[bench]
+fn bench_unsampled_trace(b: &mut Bencher) {
+    bench_trace(b, "Sampled=0")
+}
@@ -0,0 +1,238 @@
+//! Builders for segments and subsegments which continue existing traces
+//!
+//! `Segment::begin` and `Subsegment::begin` start documents now with new ids. These
+//! builders set explicit ids, start times and end times, for example to continue a trace
+//! received from another service or to backfill work which already happened.
+
+use crate::{
+    validation::sanitize_name, SamplingDecision, Seconds, Segment, SegmentId, Subsegment, TraceId,
+};
+
+/// Builds a `Segment` with explicit identifiers and start time
+#[derive(Debug, Clone)]
+pub struct SegmentBuilder {
+    segment: Segment,
+}
+
+impl SegmentBuilder {
+    /// Set the trace the segment belongs to
+    pub fn trace_id(
+        mut self,
+        trace_id: TraceId,
+    ) -> Self {
+        self.segment.trace_id = trace_id;
+        self
+    }
+
+    /// Set the id of the segment
+    pub fn id(
+        mut self,
+        id: SegmentId,
+    ) -> Self {
+        self.segment.id = id;
+        self
+    }
+
+    /// Set the id of the upstream segment or subsegment which called this service
+    pub fn parent_id(
+        mut self,
+        parent_id: SegmentId,
+    ) -> Self {
+        self.segment.parent_id = Some(parent_id);
+        self
+    }
+
+    /// Set the time the segment was started
+    pub fn start_time(
+        mut self,
+        start_time: Seconds,
+    ) -> Self {
+        self.segment.start_time = start_time;
+        self
+    }
+
+    /// Set the time the segment was closed, completing it
+    pub fn end_time(
+        mut self,
+        end_time: Seconds,
+    ) -> Self {
+        self.segment.end_time = Some(end_time);
+        self.segment.in_progress = false;
+        self
+    }
+
+    /// Set the sampling decision of the segment's trace
+    pub fn sampling_decision(
+        mut self,
+        decision: SamplingDecision,
+    ) -> Self {
+        self.segment.sampling_decision = decision;
+        self
+    }
+
+    /// Return the segment
+    pub fn build(self) -> Segment {
+        self.segment
+    }
+}
+
+/// Builds a `Subsegment` with explicit identifiers and start time
+#[derive(Debug, Clone)]
+pub struct SubsegmentBuilder {
+    subsegment: Subsegment,
+}
+
+impl SubsegmentBuilder {
+    /// Set the trace the subsegment belongs to. Required only if sending the subsegment separately
+    pub fn trace_id(
+        mut self,
+        trace_id: TraceId,
+    ) -> Self {
+        self.subsegment.trace_id = Some(trace_id);
+        self
+    }
+
+    /// Set the id of the subsegment
+    pub fn id(
+        mut self,
+        id: SegmentId,
+    ) -> Self {
+        self.subsegment.id = id;
+        self
+    }
+
+    /// Set the id of the segment or subsegment this subsegment belongs to. Required only if sending the subsegment separately
+    pub fn parent_id(
+        mut self,
+        parent_id: SegmentId,
+    ) -> Self {
+        self.subsegment.parent_id = Some(parent_id);
+        self
+    }
+
+    /// Set the time the subsegment was started
+    pub fn start_time(
+        mut self,
+        start_time: Seconds,
+    ) -> Self {
+        self.subsegment.start_time = start_time;
+        self
+    }
+
+    /// Set the time the subsegment was closed, completing it
+    pub fn end_time(
+        mut self,
+        end_time: Seconds,
+    ) -> Self {
+        self.subsegment.end_time = Some(end_time);
+        self.subsegment.in_progress = false;
+        self
+    }
+
+    /// Set the sampling decision of the subsegment's trace
+    pub fn sampling_decision(
+        mut self,
+        decision: SamplingDecision,
+    ) -> Self {
+        self.subsegment.sampling_decision = decision;
+        self
+    }
+
+    /// Return the subsegment, which is in progress unless its `end_time` was set
+    pub fn build(self) -> Subsegment {
+        self.subsegment
+    }
+}
+
+impl Segment {
+    /// Return a builder for a named segment
+    ///
+    /// Names are truncated and sanitized and the segment is not marked in progress,
+    /// as with `Segment::begin`
+    pub fn builder<N>(name: N) -> SegmentBuilder
+    where
+        N: Into<String>,
+    {
+        SegmentBuilder {
+            segment: Segment::begin(name),
+        }
+    }
+}
+
+impl Subsegment {
+    /// Return a builder for a named subsegment
+    ///
+    /// Names are truncated and sanitized and the subsegment is marked in progress,
+    /// as with `Subsegment::begin`
+    pub fn builder<N>(name: N) -> SubsegmentBuilder
+    where
+        N: Into<String>,
+    {
+        let mut valid_name = name.into();
+        sanitize_name(&mut valid_name);
+        SubsegmentBuilder {
+            subsegment: Subsegment {
+                name: valid_name,
+                type_: "subsegment".into(),
+                in_progress: true,
+                ..Subsegment::default()
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Document;
+    use std::time::Duration;
+
+    #[test]
+    fn segments_continue_traces() {
+        let trace_id = TraceId::new();
+        let parent_id = SegmentId::new();
+        let segment = Segment::builder("service")
+            .trace_id(trace_id.clone())
+            .parent_id(parent_id.clone())
+            .start_time(Duration::from_secs(1_461_096_053).into())
+            .build();
+        assert_eq!(segment.name(), "service");
+        assert_eq!(segment.trace_id(), &trace_id);
+        assert_eq!(segment.parent_id, Some(parent_id));
+        assert_eq!(segment.start_time(), &Seconds(1_461_096_053.0));
+        assert_eq!(segment.in_progress, Segment::begin("service").in_progress);
+    }
+
+    #[test]
+    fn subsegments_are_backfilled() {
+        let id = SegmentId::new();
+        let subsegment = Subsegment::builder("work\n")
+            .id(id.clone())
+            .start_time(Duration::from_secs(1_461_096_053).into())
+            .build();
+        assert_eq!(subsegment.name(), "work_");
+        assert_eq!(subsegment.id(), &id);
+        assert!(subsegment.trace_id.is_none());
+        assert!(subsegment.end_time().is_none());
+        assert!(subsegment.in_progress);
+    }
+
+    #[test]
+    fn completed_documents_are_backfilled() {
+        let segment = Segment::builder("service")
+            .start_time(Seconds(1_461_096_053.0))
+            .end_time(Seconds(1_461_096_054.0))
+            .build();
+        assert_eq!(segment.end_time(), Some(&Seconds(1_461_096_054.0)));
+        assert!(!segment.in_progress);
+        let subsegment = Subsegment::builder("work")
+            .trace_id(segment.trace_id().clone())
+            .parent_id(segment.id().clone())
+            .start_time(Seconds(1_461_096_053.2))
+            .end_time(Seconds(1_461_096_053.8))
+            .build();
+        assert_eq!(subsegment.end_time(), Some(&Seconds(1_461_096_053.8)));
+        assert!(!subsegment.in_progress);
+        assert_eq!(subsegment.validate(), Ok(()));
+    }
+}
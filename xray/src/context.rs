@@ -0,0 +1,313 @@
+//! Tracks the segments and subsegments active on the current thread
+//!
+//! Entering a document makes it reachable from code which has no reference to it,
+//! such as the panic hook installed with `install_panic_hook`. Async tasks which move
+//! between threads should make their document active each time they are polled, see `scope`.
+
+use crate::{
+    validation::sealed::Sealed, Cause, Document, Segment, SegmentId, Subsegment, Violation,
+};
+use serde_derive::Serialize;
+use std::cell::{BorrowMutError, RefCell};
+
+thread_local! {
+    static ACTIVE: RefCell<Vec<Active>> = RefCell::new(Vec::new());
+}
+
+/// A segment or subsegment which is active on the current thread
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum Active {
+    /// An active segment
+    Segment(Segment),
+    /// An active subsegment
+    Subsegment(Subsegment),
+}
+
+impl Active {
+    /// Return the id of the active document
+    pub fn id(&self) -> &SegmentId {
+        match self {
+            Active::Segment(segment) => &segment.id,
+            Active::Subsegment(subsegment) => &subsegment.id,
+        }
+    }
+
+    /// Record the cause of a server side failure and mark the active document as a fault
+    pub fn record_fault<C>(
+        &mut self,
+        cause: C,
+    ) -> &mut Self
+    where
+        C: Into<Cause>,
+    {
+        match self {
+            Active::Segment(segment) => {
+                segment.record_fault(cause);
+            }
+            Active::Subsegment(subsegment) => {
+                subsegment.record_fault(cause);
+            }
+        }
+        self
+    }
+
+    /// Begin a new subsegment of the active document
+    pub fn begin_subsegment<N>(
+        &self,
+        name: N,
+    ) -> Subsegment
+    where
+        N: Into<String>,
+    {
+        match self {
+            Active::Segment(segment) => segment.begin_subsegment(name),
+            Active::Subsegment(subsegment) => subsegment.begin_subsegment(name),
+        }
+    }
+
+    /// Record that a subsegment of the active document completed and was sent on its own
+    pub fn complete_child(
+        &mut self,
+        id: &SegmentId,
+    ) -> &mut Self {
+        match self {
+            Active::Segment(segment) => {
+                segment.complete_child(id);
+            }
+            Active::Subsegment(subsegment) => {
+                subsegment.complete_child(id);
+            }
+        }
+        self
+    }
+
+    /// End the active document by assigning its end_time
+    pub fn end(&mut self) -> &mut Self {
+        match self {
+            Active::Segment(segment) => {
+                segment.end();
+            }
+            Active::Subsegment(subsegment) => {
+                subsegment.end();
+            }
+        }
+        self
+    }
+}
+
+impl From<Segment> for Active {
+    fn from(segment: Segment) -> Self {
+        Active::Segment(segment)
+    }
+}
+
+impl From<Subsegment> for Active {
+    fn from(subsegment: Subsegment) -> Self {
+        Active::Subsegment(subsegment)
+    }
+}
+
+impl Document for Active {
+    fn validate(&self) -> std::result::Result<(), Vec<Violation>> {
+        match self {
+            Active::Segment(segment) => segment.validate(),
+            Active::Subsegment(subsegment) => subsegment.validate(),
+        }
+    }
+
+    fn repair(&mut self) {
+        match self {
+            Active::Segment(segment) => segment.repair(),
+            Active::Subsegment(subsegment) => subsegment.repair(),
+        }
+    }
+
+    fn id(&self) -> &SegmentId {
+        self.id()
+    }
+}
+
+impl Sealed for Active {
+    fn is_in_progress(&self) -> bool {
+        match self {
+            Active::Segment(segment) => segment.is_in_progress(),
+            Active::Subsegment(subsegment) => subsegment.is_in_progress(),
+        }
+    }
+
+    fn is_sampled(&self) -> bool {
+        match self {
+            Active::Segment(segment) => segment.is_sampled(),
+            Active::Subsegment(subsegment) => subsegment.is_sampled(),
+        }
+    }
+
+    fn facade_subsegments(&self) -> Option<Vec<Subsegment>> {
+        match self {
+            Active::Segment(segment) => segment.facade_subsegments(),
+            Active::Subsegment(subsegment) => subsegment.facade_subsegments(),
+        }
+    }
+
+    fn to_in_progress(&self) -> Self {
+        match self {
+            Active::Segment(segment) => Active::Segment(segment.to_in_progress()),
+            Active::Subsegment(subsegment) => Active::Subsegment(subsegment.to_in_progress()),
+        }
+    }
+}
+
+/// A document entered into the current thread's context
+///
+/// Dropping this without calling `exit` discards the document
+#[derive(Debug)]
+pub struct Entered {
+    id: SegmentId,
+}
+
+impl Entered {
+    /// Remove the document from the current thread's context and return it
+    ///
+    /// Returns `None` if the document was already flushed, for example by the panic hook
+    pub fn exit(self) -> Option<Active> {
+        remove(&self.id)
+    }
+}
+
+impl Drop for Entered {
+    fn drop(&mut self) {
+        remove(&self.id);
+    }
+}
+
+/// Make a segment or subsegment the current thread's active document
+pub fn enter<D>(document: D) -> Entered
+where
+    D: Into<Active>,
+{
+    let document = document.into();
+    let id = document.id().clone();
+    ACTIVE.with(|active| active.borrow_mut().push(document));
+    Entered { id }
+}
+
+/// Apply a function to the innermost active document on the current thread
+///
+/// Returns `None` if no document is active
+pub fn with_current<F, T>(f: F) -> Option<T>
+where
+    F: FnOnce(&mut Active) -> T,
+{
+    ACTIVE.with(|active| active.borrow_mut().last_mut().map(f))
+}
+
+/// Make a task's document active on the current thread while `f` runs
+///
+/// Tasks which move between threads hold on to their document and run each poll within
+/// `scope`. The document is handed back once `f` returns, or left as `None` if the
+/// panic hook flushed it
+pub fn scope<F, T>(
+    document: &mut Option<Active>,
+    f: F,
+) -> T
+where
+    F: FnOnce() -> T,
+{
+    let entered = document.take().map(enter);
+    let result = f();
+    *document = entered.and_then(Entered::exit);
+    result
+}
+
+/// Remove the innermost active document from the current thread
+///
+/// Fails if the documents are being accessed by `with_current`
+pub(crate) fn take_current() -> std::result::Result<Option<Active>, BorrowMutError> {
+    ACTIVE.with(|active| Ok(active.try_borrow_mut()?.pop()))
+}
+
+fn remove(id: &SegmentId) -> Option<Active> {
+    ACTIVE
+        .try_with(|active| {
+            let mut active = active.try_borrow_mut().ok()?;
+            let pos = active.iter().rposition(|document| document.id() == id)?;
+            Some(active.remove(pos))
+        })
+        .ok()
+        .and_then(|document| document)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::TraceId;
+
+    #[test]
+    fn entered_documents_are_current_until_exited() {
+        assert!(with_current(|_| ()).is_none());
+        let segment = Segment::begin("outer");
+        let segment_id = segment.id.clone();
+        let outer = enter(segment);
+        let inner = enter(Subsegment::begin(
+            TraceId::new(),
+            Some(segment_id.clone()),
+            "inner",
+        ));
+        assert_eq!(
+            with_current(|current| current.id().clone()),
+            Some(inner.id.clone())
+        );
+        assert!(inner.exit().is_some());
+        assert_eq!(
+            with_current(|current| current.id().clone()),
+            Some(segment_id)
+        );
+        drop(outer);
+        assert!(with_current(|_| ()).is_none());
+    }
+
+    #[test]
+    fn subsegments_begin_under_the_current_document() {
+        let segment = Segment::begin("outer");
+        let segment_id = segment.id.clone();
+        let _entered = enter(segment);
+        assert_eq!(
+            with_current(|current| current.begin_subsegment("inner").parent_id),
+            Some(Some(segment_id))
+        );
+    }
+
+    #[test]
+    fn exit_returns_none_once_taken() {
+        let outer = enter(Segment::begin("outer"));
+        let inner = enter(Segment::begin("taken"));
+        assert_eq!(
+            take_current()
+                .ok()
+                .and_then(|taken| taken)
+                .map(|taken| taken.id().clone()),
+            Some(inner.id.clone())
+        );
+        assert!(inner.exit().is_none());
+        assert!(outer.exit().is_some());
+    }
+
+    #[test]
+    fn scoped_documents_are_current_within_scope() {
+        let segment = Segment::begin("task");
+        let segment_id = segment.id.clone();
+        let mut document = Some(Active::from(segment));
+        assert_eq!(
+            scope(&mut document, || with_current(|current| current
+                .id()
+                .clone())),
+            Some(segment_id.clone())
+        );
+        assert!(with_current(|_| ()).is_none());
+        assert_eq!(
+            document.as_ref().map(|document| document.id().clone()),
+            Some(segment_id)
+        );
+    }
+}
@@ -10,7 +10,7 @@ use std::{
 ///
 /// A Default implementation is provided which yields the number of seconds since the epoch from
 /// the system time's `now` value
-#[derive(Debug, PartialEq)]
+#[derive(Debug, PartialEq, Clone)]
 pub struct Seconds(pub(crate) f64);
 
 impl Seconds {
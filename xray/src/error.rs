@@ -1,3 +1,4 @@
+use crate::Violation;
 use failure::Fail;
 use serde_json::Error as JsonError;
 use std::io::Error as IOError;
@@ -8,6 +9,15 @@ pub enum Error {
     IO(IOError),
     #[fail(display = "Json Error")]
     Json(JsonError),
+    #[fail(display = "Invalid segment document")]
+    Invalid(Vec<Violation>),
+    #[fail(display = "Reserved metadata namespace {}", _0)]
+    ReservedNamespace(String),
+    #[fail(
+        display = "Lineage depth {} exceeds the maximum of {}",
+        depth, max_depth
+    )]
+    RecursionLimit { depth: u32, max_depth: u32 },
 }
 
 impl From<JsonError> for Error {
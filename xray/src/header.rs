@@ -1,14 +1,14 @@
 //! X-Ray [tracing header](https://docs.aws.amazon.com/xray/latest/devguide/xray-concepts.html?shortFooter=true#xray-concepts-tracingheader)
 //! parser
 
-use crate::{SegmentId, TraceId};
+use crate::{Lineage, SegmentId, TraceId};
 use std::{
-    collections::HashMap,
     fmt::{self, Display},
     str::FromStr,
 };
 
-#[derive(PartialEq, Debug)]
+/// Whether a trace is recorded, as propagated in the `Sampled` field of a tracing header
+#[derive(PartialEq, Debug, Clone)]
 pub enum SamplingDecision {
     /// Sampled indicates the current segment has been
     /// sampled and will be sent to the X-Ray daemon.
@@ -60,13 +60,47 @@ impl Default for SamplingDecision {
     }
 }
 
+/// A field of a tracing header, in the order it was parsed or added
+#[derive(PartialEq, Debug, Clone)]
+enum Field {
+    Root,
+    Parent,
+    Sampled,
+    Data(String, String),
+}
+
 /// Parsed representation of `X-Amzn-Trace-Id` request header
-#[derive(PartialEq, Debug, Default)]
+///
+/// Fields are rendered in the order they were parsed, so headers are forwarded unchanged
+#[derive(Debug, Clone)]
 pub struct Header {
     pub(crate) trace_id: TraceId,
     pub(crate) parent_id: Option<SegmentId>,
     pub(crate) sampling_decision: SamplingDecision,
-    additional_data: HashMap<String, String>,
+    fields: Vec<Field>,
+}
+
+impl Default for Header {
+    fn default() -> Self {
+        Header {
+            trace_id: TraceId::default(),
+            parent_id: None,
+            sampling_decision: SamplingDecision::default(),
+            fields: vec![Field::Root],
+        }
+    }
+}
+
+impl PartialEq for Header {
+    fn eq(
+        &self,
+        other: &Header,
+    ) -> bool {
+        self.trace_id == other.trace_id
+            && self.parent_id == other.parent_id
+            && self.sampling_decision == other.sampling_decision
+            && self.data_fields().eq(other.data_fields())
+    }
 }
 
 impl Header {
@@ -75,6 +109,12 @@ impl Header {
     /// HTTP header values should be the Display serialization of Header structs
     pub const NAME: &'static str = "X-Amzn-Trace-Id";
 
+    /// Default maximum length in bytes of header values rendered with `to_string_limited`
+    pub const MAX_LEN: usize = 256;
+
+    const SELF: &'static str = "Self";
+    const LINEAGE: &'static str = "Lineage";
+
     pub fn new(trace_id: TraceId) -> Self {
         Header {
             trace_id,
@@ -87,6 +127,7 @@ impl Header {
         parent_id: SegmentId,
     ) -> &mut Self {
         self.parent_id = Some(parent_id);
+        self.insert_after(Field::Parent, &[Field::Root]);
         self
     }
 
@@ -95,6 +136,7 @@ impl Header {
         decision: SamplingDecision,
     ) -> &mut Self {
         self.sampling_decision = decision;
+        self.insert_after(Field::Sampled, &[Field::Parent, Field::Root]);
         self
     }
 
@@ -107,31 +149,149 @@ impl Header {
         K: Into<String>,
         V: Into<String>,
     {
-        self.additional_data.insert(key.into(), value.into());
+        let (key, value) = (key.into(), value.into());
+        match self.fields.iter_mut().find_map(|field| match field {
+            Field::Data(k, v) if *k == key => Some(v),
+            _ => None,
+        }) {
+            Some(existing) => *existing = value,
+            None => self.fields.push(Field::Data(key, value)),
+        }
         self
     }
+
+    /// Return the value of an additional field, such as `Self` or `Lineage`
+    pub fn data(
+        &self,
+        key: &str,
+    ) -> Option<&str> {
+        self.fields.iter().find_map(|field| match field {
+            Field::Data(k, v) if k == key => Some(v.as_str()),
+            _ => None,
+        })
+    }
+
+    /// Return the `Self` field load balancers add to identify the request they received
+    pub fn self_id(&self) -> Option<&str> {
+        self.data(Self::SELF)
+    }
+
+    /// Return the `Lineage` field Lambda uses to detect recursive invocations
+    ///
+    /// Returns `None` if the field is absent or malformed
+    pub fn lineage(&self) -> Option<Lineage> {
+        self.data(Self::LINEAGE)
+            .and_then(|lineage| lineage.parse().ok())
+    }
+
+    /// Set the `Lineage` field
+    pub fn with_lineage(
+        &mut self,
+        lineage: &Lineage,
+    ) -> &mut Self {
+        self.with_data(Self::LINEAGE, lineage.to_string())
+    }
+
+    /// Render the header, leaving out additional fields which would make it longer than `max_len` bytes
+    ///
+    /// The `Root`, `Parent` and `Sampled` fields are always rendered
+    pub fn to_string_limited(
+        &self,
+        max_len: usize,
+    ) -> String {
+        let mut rendered = String::new();
+        for field in &self.fields {
+            let value = match self.render(field) {
+                Some(value) => value,
+                None => continue,
+            };
+            let separator = if rendered.is_empty() { 0 } else { 1 };
+            if let Field::Data(..) = field {
+                if rendered.len() + separator + value.len() > max_len {
+                    continue;
+                }
+            }
+            if separator > 0 {
+                rendered.push(';');
+            }
+            rendered.push_str(&value);
+        }
+        rendered
+    }
+
+    fn data_fields(&self) -> impl Iterator<Item = &Field> {
+        self.fields
+            .iter()
+            .filter(|field| matches!(field, Field::Data(..)))
+    }
+
+    fn render(
+        &self,
+        field: &Field,
+    ) -> Option<String> {
+        match field {
+            Field::Root => Some(format!("Root={}", self.trace_id)),
+            Field::Parent => self
+                .parent_id
+                .as_ref()
+                .map(|parent| format!("Parent={}", parent)),
+            Field::Sampled if self.sampling_decision != SamplingDecision::Unknown => {
+                Some(self.sampling_decision.to_string())
+            }
+            Field::Sampled => None,
+            Field::Data(k, v) => Some(format!("{}={}", k, v)),
+        }
+    }
+
+    /// Add a field after the first of `preceding` present, or first, unless already present
+    fn insert_after(
+        &mut self,
+        field: Field,
+        preceding: &[Field],
+    ) {
+        if self.fields.contains(&field) {
+            return;
+        }
+        let pos = preceding
+            .iter()
+            .find_map(|preceding| self.fields.iter().position(|f| f == preceding))
+            .map_or(0, |pos| pos + 1);
+        self.fields.insert(pos, field);
+    }
 }
 
 impl FromStr for Header {
     type Err = String;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        s.split(';')
-            .try_fold(Header::default(), |mut header, line| {
+        let mut header = s.split(';').try_fold(
+            Header {
+                fields: Vec::new(),
+                ..Header::default()
+            },
+            |mut header, line| {
                 if line.starts_with("Root=") {
-                    header.trace_id = TraceId::Rendered(line[5..].into())
+                    header.trace_id = TraceId::Rendered(line[5..].into());
+                    header.fields.push(Field::Root);
                 } else if line.starts_with("Parent=") {
-                    header.parent_id = Some(SegmentId::Rendered(line[7..].into()))
-                } else if line.starts_with("Sampled=") {
+                    header.parent_id = Some(SegmentId::Rendered(line[7..].into()));
+                    header.fields.push(Field::Parent);
+                } else if SamplingDecision::from(line) != SamplingDecision::Unknown {
                     header.sampling_decision = line.into();
-                } else if !line.starts_with("Self=") {
+                    header.fields.push(Field::Sampled);
+                } else {
                     let pos = line
                         .find('=')
                         .ok_or_else(|| format!("invalid key=value: no `=` found in `{}`", s))?;
                     let (key, value) = (&line[..pos], &line[pos + 1..]);
-                    header.additional_data.insert(key.into(), value.into());
+                    header.fields.push(Field::Data(key.into(), value.into()));
                 }
-                Ok(header)
-            })
+                Ok::<_, String>(header)
+            },
+        )?;
+        if !header.fields.contains(&Field::Root) {
+            header.fields.insert(0, Field::Root);
+        }
+        Ok(header)
     }
 }
 
@@ -140,15 +300,13 @@ impl Display for Header {
         &self,
         f: &mut fmt::Formatter,
     ) -> fmt::Result {
-        write!(f, "Root={}", self.trace_id)?;
-        if let Some(parent) = &self.parent_id {
-            write!(f, ";Parent={}", parent)?;
-        }
-        if self.sampling_decision != SamplingDecision::Unknown {
-            write!(f, ";{}", self.sampling_decision)?;
-        }
-        for (k, v) in &self.additional_data {
-            write!(f, ";{}={}", k, v)?;
+        let mut first = true;
+        for value in self.fields.iter().filter_map(|field| self.render(field)) {
+            if !first {
+                write!(f, ";")?;
+            }
+            write!(f, "{}", value)?;
+            first = false;
         }
         Ok(())
     }
@@ -183,6 +341,55 @@ mod tests {
         )
     }
 
+    #[test]
+    fn round_trips_every_field_in_order() {
+        let value = "Self=1-67891234-12456789abcdef012345678;Root=1-67891233-abcdef012345678912345678;Parent=53995c3f42cd8ad8;Sampled=?;b=2;a=1;Lineage=a87bd80c:1;Sampled=x";
+        let header = value.parse::<Header>().expect("invalid header");
+        assert_eq!(header.to_string(), value);
+        assert_eq!(header.self_id(), Some("1-67891234-12456789abcdef012345678"));
+        assert_eq!(
+            header.lineage().map(|lineage| lineage.to_string()),
+            Some("a87bd80c:1".into())
+        );
+        assert_eq!(header.data("a"), Some("1"));
+    }
+
+    #[test]
+    fn added_fields_follow_the_root() {
+        let mut header = Header::new(TraceId::Rendered(
+            "1-5759e988-bd862e3fe1be46a994272793".into(),
+        ));
+        header
+            .with_data("key", "value")
+            .with_sampling_decision(SamplingDecision::Sampled)
+            .with_parent_id(SegmentId::Rendered("53995c3f42cd8ad8".into()))
+            .with_lineage(&"a87bd80c:1".parse().expect("invalid lineage"))
+            .with_data("key", "other");
+        assert_eq!(
+            header.to_string(),
+            "Root=1-5759e988-bd862e3fe1be46a994272793;Parent=53995c3f42cd8ad8;Sampled=1;key=other;Lineage=a87bd80c:1"
+        );
+    }
+
+    #[test]
+    fn limited_rendering_drops_additional_fields() {
+        let header = "Root=1-5759e988-bd862e3fe1be46a994272793;Parent=53995c3f42cd8ad8;Sampled=1;big=0123456789;small=1"
+            .parse::<Header>()
+            .expect("invalid header");
+        assert_eq!(
+            header.to_string_limited(85),
+            "Root=1-5759e988-bd862e3fe1be46a994272793;Parent=53995c3f42cd8ad8;Sampled=1;small=1"
+        );
+        assert_eq!(
+            header.to_string_limited(0),
+            "Root=1-5759e988-bd862e3fe1be46a994272793;Parent=53995c3f42cd8ad8;Sampled=1"
+        );
+        assert_eq!(
+            header.to_string_limited(Header::MAX_LEN),
+            header.to_string()
+        );
+    }
+
     #[test]
     fn displays_as_header() {
         let header = Header {
@@ -0,0 +1,134 @@
+//! Records panics as faults on the current thread's active document
+
+use crate::{context, Cause, Client, Exception, StackCapture, StackFrame};
+use std::{any::Any, panic, panic::Location, sync::Arc};
+
+/// Install a panic hook which records panics on the current thread's active document
+///
+/// The innermost active segment or subsegment is marked as a fault with an exception
+/// describing the panic message and the stack recorded with `capture`. The panic's
+/// location stands in for the stack when no frames were recorded. The document is ended
+/// and sent with `client` before the previously installed hook runs. Enclosing documents
+/// stay active, so code which recovers from the panic with `catch_unwind` can still record
+/// and send them.
+pub fn install_panic_hook(
+    client: Arc<Client>,
+    capture: StackCapture,
+) {
+    let previous = panic::take_hook();
+    panic::set_hook(Box::new(move |info| {
+        record(&client, &capture, info.payload(), info.location());
+        previous(info);
+    }));
+}
+
+fn record(
+    client: &Client,
+    capture: &StackCapture,
+    payload: &(dyn Any + Send),
+    location: Option<&Location>,
+) {
+    let mut document = match context::take_current() {
+        Ok(Some(document)) => document,
+        Ok(None) => return,
+        Err(_) => {
+            log::warn!("The active document was in use when panicking and could not be recorded");
+            return;
+        }
+    };
+    let mut exception = Exception::new(message(payload));
+    exception.type_ = Some("panic".into());
+    let mut cause = Cause::from_exceptions(vec![exception]).with_stack(capture);
+    if let Cause::Description { exceptions, .. } = &mut cause {
+        if let (Some(exception), Some(location)) = (exceptions.first_mut(), location) {
+            locate(exception, location.file(), location.line());
+        }
+    }
+    document.record_fault(cause).end();
+    if let Err(err) = client.send_document(&document) {
+        log::warn!("Failed to send document for panic: {}", err);
+    }
+}
+
+/// Record the panic's location as the exception's stack if no frames were recorded,
+/// otherwise the panicking function's frame is already at the top of the stack
+fn locate(
+    exception: &mut Exception,
+    file: &str,
+    line: u32,
+) {
+    if exception.stack.is_empty() {
+        exception.stack.push(StackFrame {
+            path: Some(file.into()),
+            line: Some(line),
+            label: None,
+        });
+    }
+}
+
+fn message(payload: &(dyn Any + Send)) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(|message| (*message).to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "Box<Any>".into())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Segment, Subsegment};
+    use serde_json::Value;
+    use std::{net::UdpSocket, time::Duration};
+
+    #[test]
+    fn panics_are_recorded_as_faults() {
+        let daemon = UdpSocket::bind("127.0.0.1:0").expect("failed to bind socket");
+        daemon
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .expect("failed to set timeout");
+        let client = Client::new(daemon.local_addr().expect("failed to get address"))
+            .expect("failed to create client");
+        install_panic_hook(Arc::new(client), StackCapture::new());
+
+        let segment = Segment::begin("handler");
+        let subsegment =
+            Subsegment::begin(segment.trace_id.clone(), Some(segment.id.clone()), "work");
+        let segment = context::enter(segment);
+        let subsegment = context::enter(subsegment);
+        assert!(panic::catch_unwind(|| panic!("boom")).is_err());
+
+        let mut buf = [0; 8192];
+        let len = daemon.recv(&mut buf).expect("failed to receive document");
+        let packet = String::from_utf8_lossy(&buf[..len]).to_string();
+        let (_, body) = packet.split_at(packet.find("\\n").expect("no header") + 2);
+        let document = serde_json::from_str::<Value>(body).expect("invalid document");
+        assert_eq!(document["name"], "work");
+        assert_eq!(document["fault"], true);
+        assert!(document["end_time"].is_number());
+        let exception = &document["cause"]["exceptions"][0];
+        assert_eq!(exception["message"], "boom");
+        assert_eq!(exception["type"], "panic");
+        assert_eq!(exception["stack"][0]["path"], file!());
+        assert!(subsegment.exit().is_none());
+
+        // the enclosing segment survives panics recovered by catch_unwind
+        match segment.exit() {
+            Some(context::Active::Segment(segment)) => {
+                assert_eq!(segment.name(), "handler");
+                assert!(!segment.fault && segment.end_time.is_none());
+            }
+            other => panic!("unexpected document {:?}", other),
+        }
+        assert!(context::with_current(|_| ()).is_none());
+    }
+
+    #[test]
+    fn locations_only_stand_in_for_missing_stacks() {
+        let mut exception = Exception::new("boom");
+        locate(&mut exception, "src/main.rs", 1);
+        assert_eq!(exception.stack[0].line, Some(1));
+        locate(&mut exception, "src/main.rs", 2);
+        assert_eq!(exception.stack.len(), 1);
+    }
+}
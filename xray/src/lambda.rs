@@ -1,23 +1,109 @@
-use crate::Header;
+//! Support for tracing AWS Lambda functions
+//!
+//! Lambda records a segment for each invocation itself. Functions continue that segment
+//! through a facade: a `Segment` carrying the invocation's trace and segment ids which
+//! is never sent. Clients send the subsegments embedded in a facade as independent
+//! subsegments of the Lambda-owned segment instead.
+
+use crate::{Header, Lineage, SamplingDecision, Segment};
 use std::{
     env::var,
     fs::{create_dir_all, File},
+    io,
 };
 
-pub(crate) fn init() -> std::io::Result<()> {
-    if taskRoot().is_some() {
+/// Response header of the Lambda Runtime API's next invocation request which carries
+/// the invocation's tracing header
+pub const RUNTIME_TRACE_ID_HEADER: &str = "Lambda-Runtime-Trace-Id";
+
+/// Return true if this process is running in AWS Lambda
+pub fn is_lambda() -> bool {
+    task_root().is_some()
+}
+
+/// Signal to Lambda that the function is instrumented with X-Ray
+///
+/// This is done when clients are created, and does nothing outside of Lambda
+pub fn init() -> io::Result<()> {
+    if is_lambda() {
         create_dir_all("/tmp/.aws-xray")?;
         File::create("/tmp/.aws-xray/initialized")?;
     }
     Ok(())
 }
 
-pub(crate) fn taskRoot() -> Option<String> {
-    var("LAMBDA_TASK_ROOT").ok()
+/// Return the facade segment of an invocation from the value of its
+/// `Lambda-Runtime-Trace-Id` header
+///
+/// Returns `None` if the header is malformed or has no parent, which holds
+/// the id of the Lambda-owned segment
+pub fn facade(trace_header: &str) -> Option<Segment> {
+    let header = trace_header.parse::<Header>().ok()?;
+    if !header.trace_id.is_valid() {
+        return None;
+    }
+    let id = header.parent_id.clone().filter(|id| id.is_valid())?;
+    let mut segment = Segment {
+        trace_id: header.trace_id.clone(),
+        id,
+        name: function_name().unwrap_or_default(),
+        in_progress: true,
+        sampling_decision: match header.sampling_decision {
+            SamplingDecision::NotSampled => SamplingDecision::NotSampled,
+            _ => SamplingDecision::Sampled,
+        },
+        lineage: header.lineage(),
+        facade: true,
+        ..Segment::default()
+    };
+    if let Some(function) = function_name() {
+        segment.increment_lineage(&Lineage::function_hash(&function));
+    }
+    Some(segment)
 }
 
-pub(crate) fn header() -> Option<Header> {
+/// Return the facade segment of the current invocation from the `_X_AMZN_TRACE_ID`
+/// env variable
+///
+/// Runtimes built on the Runtime API should prefer `facade` with each invocation's
+/// `Lambda-Runtime-Trace-Id` header, as this variable is only updated by runtimes which set it
+pub fn env_facade() -> Option<Segment> {
     var("_X_AMZN_TRACE_ID")
         .ok()
-        .and_then(|value| value.parse::<Header>().ok())
+        .and_then(|value| facade(&value))
+}
+
+fn task_root() -> Option<String> {
+    var("LAMBDA_TASK_ROOT").ok()
+}
+
+fn function_name() -> Option<String> {
+    var("AWS_LAMBDA_FUNCTION_NAME").ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn facades_continue_the_lambda_segment() {
+        let segment =
+            facade("Root=1-5759e988-bd862e3fe1be46a994272793;Parent=53995c3f42cd8ad8;Sampled=1")
+                .expect("no facade");
+        assert!(segment.facade);
+        assert!(segment.is_sampled());
+        assert_eq!(segment.id.to_string(), "53995c3f42cd8ad8");
+        let subsegment = segment.begin_subsegment("work");
+        assert_eq!(
+            subsegment.trace_id.map(|trace_id| trace_id.to_string()),
+            Some("1-5759e988-bd862e3fe1be46a994272793".into())
+        );
+        assert_eq!(subsegment.parent_id, Some(segment.id));
+    }
+
+    #[test]
+    fn facades_require_a_parent() {
+        assert!(facade("Root=1-5759e988-bd862e3fe1be46a994272793;Sampled=1").is_none());
+        assert!(facade("Root=bogus;Parent=53995c3f42cd8ad8").is_none());
+    }
 }
@@ -4,24 +4,41 @@
 
 use serde::Serialize;
 use std::{
+    borrow::Cow,
+    collections::{HashMap, VecDeque},
     env,
     net::{SocketAddr, UdpSocket},
     result::Result as StdResult,
-    sync::Arc,
+    sync::{Arc, Mutex, MutexGuard},
 };
 
+mod builder;
+pub mod context;
 mod epoch;
 mod error;
 mod header;
 mod hexbytes;
-mod lambda;
+mod hook;
+pub mod lambda;
+mod lineage;
 mod segment;
 mod segment_id;
+mod stack;
 mod trace_id;
+mod validation;
 
 pub use crate::{
-    epoch::Seconds, error::Error, header::Header, segment::*, segment_id::SegmentId,
+    builder::{SegmentBuilder, SubsegmentBuilder},
+    epoch::Seconds,
+    error::Error,
+    header::{Header, SamplingDecision},
+    hook::install_panic_hook,
+    lineage::{Lineage, LineageEntry, RecursionGuard},
+    segment::*,
+    segment_id::SegmentId,
+    stack::StackCapture,
     trace_id::TraceId,
+    validation::{Document, Validation, Violation, ViolationKind},
 };
 
 /// Type alias for Results which may return `xray::Errors`
@@ -31,6 +48,113 @@ pub type Result<T> = StdResult<T, Error>;
 #[derive(Debug)]
 pub struct Client {
     socket: Arc<UdpSocket>,
+    validation: Validation,
+    emitted: Mutex<Emitted>,
+}
+
+/// Ids of documents a client has already sent
+#[derive(Debug, Default)]
+struct Emitted {
+    in_progress: Recent,
+    complete: Recent,
+}
+
+impl Emitted {
+    /// Record `id` as sent in progress, returning false if it already was or
+    /// its completed document was sent
+    fn in_progress(
+        &mut self,
+        id: &str,
+    ) -> bool {
+        if self.in_progress.contains(id) || self.complete.contains(id) {
+            return false;
+        }
+        self.in_progress.insert(id);
+        true
+    }
+
+    /// Record `id` as sent complete, returning false if it already was
+    ///
+    /// The id is kept as sent in progress until `completed` confirms the
+    /// completed document was sent
+    fn complete(
+        &mut self,
+        id: &str,
+    ) -> bool {
+        if self.complete.contains(id) {
+            return false;
+        }
+        self.complete.insert(id);
+        true
+    }
+
+    /// Forget `id` as sent in progress once its completed document was sent
+    fn completed(
+        &mut self,
+        id: &str,
+    ) {
+        self.in_progress.remove(id);
+    }
+}
+
+/// A set of ids which forgets the oldest ids beyond its capacity
+#[derive(Debug)]
+struct Recent {
+    capacity: usize,
+    ids: HashMap<String, u64>,
+    order: VecDeque<(u64, String)>,
+    inserted: u64,
+}
+
+impl Default for Recent {
+    fn default() -> Self {
+        Recent::new(Recent::DEFAULT_CAPACITY)
+    }
+}
+
+impl Recent {
+    /// Number of ids remembered to refuse duplicates
+    const DEFAULT_CAPACITY: usize = 1024;
+
+    fn new(capacity: usize) -> Self {
+        Recent {
+            capacity,
+            ids: HashMap::new(),
+            order: VecDeque::new(),
+            inserted: 0,
+        }
+    }
+
+    fn contains(
+        &self,
+        id: &str,
+    ) -> bool {
+        self.ids.contains_key(id)
+    }
+
+    fn insert(
+        &mut self,
+        id: &str,
+    ) {
+        self.inserted += 1;
+        self.ids.insert(id.into(), self.inserted);
+        self.order.push_back((self.inserted, id.into()));
+        while self.order.len() > self.capacity {
+            if let Some((inserted, id)) = self.order.pop_front() {
+                // ids removed and inserted again are only forgotten with their latest insertion
+                if self.ids.get(&id) == Some(&inserted) {
+                    self.ids.remove(&id);
+                }
+            }
+        }
+    }
+
+    fn remove(
+        &mut self,
+        id: &str,
+    ) {
+        self.ids.remove(id);
+    }
 }
 
 impl Default for Client {
@@ -61,7 +185,26 @@ impl Client {
         let socket = Arc::new(UdpSocket::bind(&[([0, 0, 0, 0], 0).into()][..])?);
         socket.set_nonblocking(true)?;
         socket.connect(&addr)?;
-        Ok(Client { socket })
+        if let Err(err) = lambda::init() {
+            log::debug!("Failed to initialize X-Ray for Lambda: {}", err);
+        }
+        Ok(Client {
+            socket,
+            validation: Validation::default(),
+            emitted: Mutex::default(),
+        })
+    }
+
+    /// Return a client which checks documents against X-Ray rules
+    /// before sending them
+    ///
+    /// By default documents are sent without being checked
+    pub fn with_validation(
+        mut self,
+        validation: Validation,
+    ) -> Self {
+        self.validation = validation;
+        self
     }
 
     #[inline]
@@ -74,6 +217,9 @@ impl Client {
     }
 
     /// send a segment to the xray daemon this client is connected to
+    ///
+    /// Data is sent as it is. Use `send_document` to send segments and subsegments
+    /// according to X-Ray rules
     pub fn send<S>(
         &self,
         data: &S,
@@ -84,11 +230,121 @@ impl Client {
         self.socket.send(&Self::packet(data)?)?;
         Ok(())
     }
+
+    /// send a segment or subsegment document to the xray daemon this client is connected to
+    ///
+    /// Documents which break X-Ray rules are refused or repaired
+    /// according to this client's `Validation`. Documents still in progress are
+    /// sent as with `send_in_progress` and at most one completed document is sent per id.
+    /// Documents of unsampled traces are never sent, and Lambda facades are sent as their subsegments
+    pub fn send_document<D>(
+        &self,
+        data: &D,
+    ) -> Result<()>
+    where
+        D: Document,
+    {
+        if !data.is_sampled() {
+            return Ok(());
+        }
+        if let Some(subsegments) = data.facade_subsegments() {
+            return subsegments
+                .iter()
+                .try_for_each(|subsegment| self.send_document(subsegment));
+        }
+        if data.is_in_progress() {
+            return self.send_in_progress(data);
+        }
+        let id = data.id().to_string();
+        if !self.emitted().complete(&id) {
+            log::debug!("Document {} was already sent, skipping", id);
+            return Ok(());
+        }
+        match self.emit(data) {
+            Ok(()) => {
+                self.emitted().completed(&id);
+                Ok(())
+            }
+            Err(err) => {
+                self.emitted().complete.remove(&id);
+                Err(err)
+            }
+        }
+    }
+
+    /// send an in-progress copy of a segment to the xray daemon this client is connected to
+    ///
+    /// This makes long running requests visible before they complete. The completed
+    /// document should later be sent with `send_document`, which replaces the in-progress one.
+    /// At most one in-progress document is sent per id, and none once the completed
+    /// document was sent
+    pub fn send_in_progress<D>(
+        &self,
+        data: &D,
+    ) -> Result<()>
+    where
+        D: Document,
+    {
+        if !data.is_sampled() {
+            return Ok(());
+        }
+        if let Some(subsegments) = data.facade_subsegments() {
+            return subsegments
+                .iter()
+                .try_for_each(|subsegment| self.send_in_progress(subsegment));
+        }
+        let id = data.id().to_string();
+        if !self.emitted().in_progress(&id) {
+            log::debug!("Document {} was already sent, skipping", id);
+            return Ok(());
+        }
+        self.emit(&data.to_in_progress()).map_err(|err| {
+            self.emitted().in_progress.remove(&id);
+            err
+        })
+    }
+
+    /// Lock the ids of sent documents. Ids are recorded before their documents are
+    /// sent, and forgotten if sending fails, so the lock is never held while sending
+    fn emitted(&self) -> MutexGuard<Emitted> {
+        self.emitted.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn emit<D>(
+        &self,
+        data: &D,
+    ) -> Result<()>
+    where
+        D: Document,
+    {
+        self.send(self.validated(data)?.as_ref())
+    }
+
+    fn validated<'a, D>(
+        &self,
+        data: &'a D,
+    ) -> Result<Cow<'a, D>>
+    where
+        D: Document,
+    {
+        let data = match self.validation {
+            Validation::Skip => return Ok(Cow::Borrowed(data)),
+            Validation::Refuse => Cow::Borrowed(data),
+            Validation::Repair => {
+                let mut repaired = data.clone();
+                repaired.repair();
+                Cow::Owned(repaired)
+            }
+        };
+        data.validate().map_err(Error::Invalid)?;
+        Ok(data)
+    }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::time::Duration;
     #[test]
     fn client_prefixes_packets_with_header() {
         assert_eq!(
@@ -99,4 +355,201 @@ mod tests {
             br#"{"format": "json", "version": 1}\n{"foo":"bar"}"#.to_vec()
         )
     }
+
+    fn daemon() -> (UdpSocket, Client) {
+        let daemon = UdpSocket::bind("127.0.0.1:0").expect("failed to bind socket");
+        let client = Client::new(daemon.local_addr().expect("failed to get address"))
+            .expect("failed to create client");
+        (daemon, client)
+    }
+
+    /// Receive `expected` documents, then wait briefly to make sure no more were sent
+    fn received(
+        daemon: &UdpSocket,
+        expected: usize,
+    ) -> Vec<serde_json::Value> {
+        let mut documents = Vec::new();
+        let mut buf = [0; 8192];
+        loop {
+            let timeout = if documents.len() < expected {
+                Duration::from_secs(5)
+            } else {
+                Duration::from_millis(50)
+            };
+            daemon
+                .set_read_timeout(Some(timeout))
+                .expect("failed to set read timeout");
+            let len = match daemon.recv(&mut buf) {
+                Ok(len) => len,
+                Err(_) => break,
+            };
+            let packet = String::from_utf8_lossy(&buf[..len]).to_string();
+            let (_, body) = packet.split_at(packet.find("\\n").expect("no header") + 2);
+            documents.push(serde_json::from_str(body).expect("invalid document"));
+        }
+        assert_eq!(documents.len(), expected);
+        documents
+    }
+
+    #[test]
+    fn client_sends_serializable_data_as_it_is() {
+        let (daemon, client) = daemon();
+        let segment = Segment::begin("unfinished");
+        client
+            .with_validation(Validation::Refuse)
+            .send(&segment)
+            .expect("failed to send");
+        let documents = received(&daemon, 1);
+        assert_eq!(documents[0]["name"], "unfinished");
+    }
+
+    #[test]
+    fn client_sends_one_in_progress_and_one_complete_document() {
+        let (daemon, client) = daemon();
+        let mut segment = Segment::begin("long poll");
+        segment.end();
+        client
+            .send_in_progress(&segment)
+            .expect("failed to send in progress");
+        client
+            .send_in_progress(&segment)
+            .expect("failed to send in progress");
+        client.send_document(&segment).expect("failed to send");
+        client.send_document(&segment).expect("failed to send");
+        client
+            .send_in_progress(&segment)
+            .expect("failed to send in progress");
+        let documents = received(&daemon, 2);
+        assert_eq!(documents[0]["in_progress"], true);
+        assert!(documents[0]["end_time"].is_null());
+        assert!(documents[1]["in_progress"].is_null());
+        assert!(documents[1]["end_time"].is_number());
+    }
+
+    #[test]
+    fn client_sends_in_progress_documents_once() {
+        let (daemon, client) = daemon();
+        let mut segment = Segment::begin("long poll");
+        segment.in_progress = true;
+        client.send_document(&segment).expect("failed to send");
+        client.send_document(&segment).expect("failed to send");
+        let documents = received(&daemon, 1);
+        assert_eq!(documents[0]["in_progress"], true);
+    }
+
+    #[test]
+    fn client_sends_complete_documents_once() {
+        let (daemon, client) = daemon();
+        let mut segment = Segment::begin("request");
+        segment.end();
+        client.send_document(&segment).expect("failed to send");
+        client.send_document(&segment).expect("failed to send");
+        received(&daemon, 1);
+    }
+
+    #[test]
+    fn client_keeps_in_progress_documents_sent_when_completing_fails() {
+        let (daemon, client) = daemon();
+        let client = client.with_validation(Validation::Refuse);
+        let mut segment = Segment::begin("long poll");
+        segment.end_time = Some(Seconds(segment.start_time.0 - 1.0));
+        client
+            .send_in_progress(&segment)
+            .expect("failed to send in progress");
+        assert!(client.send_document(&segment).is_err());
+        client
+            .send_in_progress(&segment)
+            .expect("failed to send in progress");
+        segment.end();
+        client.send_document(&segment).expect("failed to send");
+        let documents = received(&daemon, 2);
+        assert_eq!(documents[0]["in_progress"], true);
+        assert!(documents[1]["end_time"].is_number());
+    }
+
+    #[test]
+    fn recent_ids_forget_the_oldest_beyond_capacity() {
+        let mut recent = Recent::new(2);
+        recent.insert("a");
+        recent.insert("b");
+        recent.remove("a");
+        recent.insert("a");
+        recent.insert("c");
+        assert!(!recent.contains("b"));
+        assert!(recent.contains("a"));
+        assert!(recent.contains("c"));
+        assert_eq!(recent.ids.len(), 2);
+        assert!(recent.order.len() <= 2);
+    }
+
+    #[test]
+    fn client_never_sends_unsampled_documents() {
+        let (daemon, client) = daemon();
+        let header = "Root=1-5759e988-bd862e3fe1be46a994272793;Sampled=0"
+            .parse::<Header>()
+            .expect("invalid header");
+        let mut segment = Segment::from_header("unsampled", &header);
+        let subsegment = segment.begin_subsegment("child");
+        client
+            .send_in_progress(&segment)
+            .expect("failed to send in progress");
+        client.send_document(&subsegment).expect("failed to send");
+        segment.end();
+        client.send_document(&segment).expect("failed to send");
+        received(&daemon, 0);
+    }
+
+    #[test]
+    fn client_sends_lambda_facades_as_subsegments() {
+        let (daemon, client) = daemon();
+        let mut segment = lambda::facade(
+            "Root=1-5759e988-bd862e3fe1be46a994272793;Parent=53995c3f42cd8ad8;Sampled=1",
+        )
+        .expect("no facade");
+        let mut subsegment = segment.begin_subsegment("work");
+        subsegment.annotate("key", "value");
+        segment.end_subsegment(subsegment);
+        segment.end();
+        client.send_document(&segment).expect("failed to send");
+        let documents = received(&daemon, 1);
+        assert_eq!(documents[0]["name"], "work");
+        assert_eq!(documents[0]["type"], "subsegment");
+        assert_eq!(
+            documents[0]["trace_id"],
+            "1-5759e988-bd862e3fe1be46a994272793"
+        );
+        assert_eq!(documents[0]["parent_id"], "53995c3f42cd8ad8");
+    }
+
+    #[test]
+    fn client_refuses_invalid_documents() {
+        let client = Client::new(([127, 0, 0, 1], 2000).into())
+            .expect("failed to create client")
+            .with_validation(Validation::Refuse);
+        match client.send_document(&Segment::begin("unfinished")) {
+            Err(Error::Invalid(violations)) => assert_eq!(
+                violations
+                    .into_iter()
+                    .map(|violation| violation.kind)
+                    .collect::<Vec<_>>(),
+                vec![ViolationKind::MissingEndTime]
+            ),
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn client_repairs_invalid_documents() {
+        let client = Client::new(([127, 0, 0, 1], 2000).into())
+            .expect("failed to create client")
+            .with_validation(Validation::Repair);
+        let segment = Segment::begin("unfinished");
+        assert_eq!(
+            client
+                .validated(&segment)
+                .map(|repaired| repaired.end_time.is_some())
+                .ok(),
+            Some(true)
+        );
+    }
 }
@@ -0,0 +1,212 @@
+//! Lambda recursion detection with the tracing header's `Lineage` field
+//!
+//! Each entry pairs a hash identifying a function with the number of times
+//! that function was invoked along the current chain of calls, for example
+//! `Lineage=a87bd80c:1|68fd508a:5`.
+
+use crate::{Error, Header, Result};
+use std::{
+    fmt::{self, Display},
+    str::FromStr,
+};
+
+/// The invocation count of a single function in a `Lineage`
+#[derive(Debug, Clone, PartialEq)]
+pub struct LineageEntry {
+    /// Hash identifying the function, in 8 hexadecimal digits
+    pub hash: String,
+    /// Number of times the function was invoked along the chain of calls
+    pub counter: u32,
+}
+
+/// Parsed representation of a tracing header's `Lineage` field
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Lineage {
+    entries: Vec<LineageEntry>,
+}
+
+impl Lineage {
+    /// Return the hash identifying a function, such as its ARN, in a lineage
+    pub fn function_hash(function: &str) -> String {
+        // 32 bit FNV-1a, which is stable across processes and Rust versions
+        let hash = function.bytes().fold(0x811c_9dc5_u32, |hash, byte| {
+            (hash ^ u32::from(byte)).wrapping_mul(0x0100_0193)
+        });
+        format!("{:08x}", hash)
+    }
+
+    /// Return the invocation counts of each function
+    pub fn entries(&self) -> &[LineageEntry] {
+        &self.entries
+    }
+
+    /// Return the highest number of times any single function was invoked
+    pub fn depth(&self) -> u32 {
+        self.entries
+            .iter()
+            .map(|entry| entry.counter)
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// Count an invocation of the function identified by `hash`
+    ///
+    /// Call this when propagating the lineage from a Lambda function
+    pub fn increment(
+        &mut self,
+        hash: &str,
+    ) -> &mut Self {
+        match self.entries.iter_mut().find(|entry| entry.hash == hash) {
+            Some(entry) => entry.counter = entry.counter.saturating_add(1),
+            None => self.entries.push(LineageEntry {
+                hash: hash.into(),
+                counter: 1,
+            }),
+        }
+        self
+    }
+}
+
+impl FromStr for Lineage {
+    type Err = String;
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        s.split('|')
+            .map(|entry| {
+                let pos = entry
+                    .rfind(':')
+                    .ok_or_else(|| format!("invalid lineage entry: no `:` found in `{}`", entry))?;
+                let (hash, counter) = (&entry[..pos], &entry[pos + 1..]);
+                Ok(LineageEntry {
+                    hash: hash.into(),
+                    counter: counter
+                        .parse()
+                        .map_err(|_| format!("invalid lineage counter `{}`", counter))?,
+                })
+            })
+            .collect::<std::result::Result<_, _>>()
+            .map(|entries| Lineage { entries })
+    }
+}
+
+impl Display for Lineage {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter,
+    ) -> fmt::Result {
+        for (i, entry) in self.entries.iter().enumerate() {
+            if i > 0 {
+                write!(f, "|")?;
+            }
+            write!(f, "{}:{}", entry.hash, entry.counter)?;
+        }
+        Ok(())
+    }
+}
+
+/// Refuses downstream calls of traces whose lineage is deeper than a threshold
+///
+/// This stops functions which invoke themselves, directly or through other services,
+/// from doing so indefinitely
+#[derive(Debug, Clone)]
+pub struct RecursionGuard {
+    max_depth: u32,
+}
+
+impl Default for RecursionGuard {
+    fn default() -> Self {
+        RecursionGuard {
+            max_depth: RecursionGuard::DEFAULT_MAX_DEPTH,
+        }
+    }
+}
+
+impl RecursionGuard {
+    /// Default maximum number of times a function may appear in a chain of calls
+    pub const DEFAULT_MAX_DEPTH: u32 = 16;
+
+    /// Return a new guard with the default maximum depth
+    pub fn new() -> Self {
+        RecursionGuard::default()
+    }
+
+    /// Set the maximum number of times a function may appear in a chain of calls
+    pub fn max_depth(
+        mut self,
+        max_depth: u32,
+    ) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// Return an error if a downstream call with this header would exceed the maximum depth
+    pub fn check(
+        &self,
+        header: &Header,
+    ) -> Result<()> {
+        let depth = header.lineage().map_or(0, |lineage| lineage.depth());
+        if depth > self.max_depth {
+            return Err(Error::RecursionLimit {
+                depth,
+                max_depth: self.max_depth,
+            });
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lineage_round_trips() {
+        let lineage = "a87bd80c:1|68fd508a:5"
+            .parse::<Lineage>()
+            .expect("invalid lineage");
+        assert_eq!(
+            lineage.entries(),
+            &[
+                LineageEntry {
+                    hash: "a87bd80c".into(),
+                    counter: 1
+                },
+                LineageEntry {
+                    hash: "68fd508a".into(),
+                    counter: 5
+                }
+            ]
+        );
+        assert_eq!(lineage.depth(), 5);
+        assert_eq!(lineage.to_string(), "a87bd80c:1|68fd508a:5");
+        assert!("a87bd80c".parse::<Lineage>().is_err());
+        assert!("a87bd80c:x".parse::<Lineage>().is_err());
+    }
+
+    #[test]
+    fn lineage_increments_per_function() {
+        let mut lineage = Lineage::default();
+        let hash = Lineage::function_hash("arn:aws:lambda:us-east-1:123456789012:function:f");
+        assert_eq!(hash.len(), 8);
+        lineage
+            .increment(&hash)
+            .increment("68fd508a")
+            .increment(&hash);
+        assert_eq!(lineage.to_string(), format!("{}:2|68fd508a:1", hash));
+    }
+
+    #[test]
+    fn guard_refuses_deep_lineages() {
+        let mut header = Header::default();
+        let guard = RecursionGuard::new().max_depth(2);
+        assert!(guard.check(&header).is_ok());
+        header.with_lineage(&"a87bd80c:2".parse().expect("invalid lineage"));
+        assert!(guard.check(&header).is_ok());
+        header.with_lineage(&"a87bd80c:3".parse().expect("invalid lineage"));
+        match guard.check(&header) {
+            Err(Error::RecursionLimit { depth, max_depth }) => {
+                assert_eq!((depth, max_depth), (3, 2))
+            }
+            other => panic!("unexpected result {:?}", other),
+        }
+    }
+}
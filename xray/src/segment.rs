@@ -1,14 +1,22 @@
-use crate::{Seconds, SegmentId, TraceId};
+use crate::{
+    validation::{sanitize_annotation_key, sanitize_name},
+    Error, Header, Lineage, Result, SamplingDecision, Seconds, SegmentId, TraceId,
+};
+use failure::Fail;
+use serde::Serialize;
 use serde_derive::{Deserialize, Serialize};
 use serde_json::Value;
-use std::{collections::HashMap, ops::Not};
+use std::{collections::HashMap, env, error::Error as StdError, ops::Not};
 
 // https://docs.aws.amazon.com/xray/latest/devguide/xray-api-sendingdata.html
 // https://docs.aws.amazon.com/xray/latest/devguide/xray-api-segmentdocuments.html
 
+/// Metadata namespaces beginning with this prefix are reserved for AWS
+const RESERVED_METADATA_PREFIX: &str = "AWS.";
+
 /// Description of an internal application operation
 /// which may be an extension of an external operation
-#[derive(Debug, Default, Serialize)]
+#[derive(Debug, Default, Clone, Serialize)]
 pub struct Segment {
     /// A unique identifier that connects all segments and subsegments originating from a single client request.
     pub(crate) trace_id: TraceId,
@@ -20,6 +28,15 @@ pub struct Segment {
     pub(crate) name: String,
     /// Number that is the time the segment was created, in floating point seconds in epoch time.
     pub(crate) start_time: Seconds,
+    /// The sampling decision propagated to downstream calls
+    #[serde(skip)]
+    pub(crate) sampling_decision: SamplingDecision,
+    /// The chain of function invocations propagated to downstream calls
+    #[serde(skip)]
+    pub(crate) lineage: Option<Lineage>,
+    /// Whether this segment stands in for a segment recorded by AWS Lambda
+    #[serde(skip)]
+    pub(crate) facade: bool,
     #[serde(skip_serializing_if = "Option::is_none")]
     /// Number that is the time the segment was closed.
     pub end_time: Option<Seconds>,
@@ -49,7 +66,7 @@ pub struct Segment {
     /// A string that identifies the user who sent the request.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub user: Option<String>,
-    ///
+    /// An AWS resource ARN that identifies the AWS resource running your application.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub resource_arn: Option<String>,
     /// http objects with information about the original HTTP request.
@@ -58,19 +75,25 @@ pub struct Segment {
     /// annotations object with key-value pairs that you want X-Ray to index for search.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub annotations: Option<HashMap<String, Annotation>>,
-    /// metadata object with any additional data that you want to store in the segment.
+    /// metadata object with any additional data that you want to store in the segment, keyed by namespace.
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub metadata: Option<HashMap<String, Value>>,
+    pub metadata: Option<HashMap<String, HashMap<String, Value>>>,
     /// aws object with information about the AWS resource on which your application served the request.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub aws: Option<Aws>,
     /// An object with information about your application.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub service: Option<Service>,
+    /// array of subsegment objects.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub subsegments: Vec<Subsegment>,
+    /// Ids of completed subsegments which were sent on their own rather than embedded
+    #[serde(skip)]
+    pub(crate) completed_ids: Vec<SegmentId>,
 }
 
 ///  An object with information about your application.
-#[derive(Debug, Default, Serialize)]
+#[derive(Debug, Default, Clone, Serialize)]
 pub struct Service {
     /// A string that identifies the version of your application that served the request.
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -78,7 +101,7 @@ pub struct Service {
 }
 
 /// Context information about the AWS environment this segment was run in
-#[derive(Debug, Default, Serialize)]
+#[derive(Debug, Default, Clone, Serialize)]
 pub struct Aws {
     ///  If your application sends segments to a different AWS account, record the ID of the account running your application.
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -92,36 +115,61 @@ pub struct Aws {
     /// Information about an Elastic Beanstalk environment. You can find this information in a file named /var/elasticbeanstalk/xray/environment.conf on the latest Elastic Beanstalk platforms.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub elastic_beanstalk: Option<ElasticBeanstalk>,
+    /// Metadata about the instrumentation used, as defined by the version 1.0 schema.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub tracing: Option<Tracing>,
+    /// Metadata about the type and version of instrumentation used.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub xray: Option<XRay>,
 }
 
-#[derive(Debug, Default, Serialize)]
+/// Metadata about the type and version of instrumentation used.
+#[derive(Debug, Default, Clone, Serialize)]
 pub struct XRay {
+    /// The version of the SDK or agent being used.
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub sdk_version: Option<String>,
+    /// The type of instrumentation, for example `X-Ray for Rust`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sdk: Option<String>,
+    /// boolean indicating whether automatic instrumentation was used.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub auto_instrumentation: Option<bool>,
 }
 
-#[derive(Debug, Default, Serialize)]
+/// Information about an Amazon ECS container.
+#[derive(Debug, Default, Clone, Serialize)]
 pub struct Ecs {
-    /// The container ID of the container running your application.
+    /// The hostname of the container running your application.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub container: Option<String>,
+    /// The full container ID of the container running your application.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub container_id: Option<String>,
+    /// The ARN of the container instance.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub container_arn: Option<String>,
 }
 
-#[derive(Debug, Default, Serialize)]
+/// Information about an EC2 instance.
+#[derive(Debug, Default, Clone, Serialize)]
 pub struct Ec2 {
     /// The instance ID of the EC2 instance.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub instance_id: Option<String>,
+    /// The type of the EC2 instance.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub instance_size: Option<String>,
+    /// The Amazon Machine Image ID.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub ami_id: Option<String>,
     /// The Availability Zone in which the instance is running.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub availability_zone: Option<String>,
 }
 
 /// Information about an Elastic Beanstalk environment. You can find this information in a file named /var/elasticbeanstalk/xray/environment.conf on the latest Elastic Beanstalk platforms.
-#[derive(Debug, Default, Serialize)]
+#[derive(Debug, Default, Clone, Serialize)]
 pub struct ElasticBeanstalk {
     /// The name of the environment.
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -134,9 +182,11 @@ pub struct ElasticBeanstalk {
     pub deployment_id: Option<usize>,
 }
 
-#[derive(Debug, Default, Serialize)]
+/// Metadata about the instrumentation used, as defined by the version 1.0 schema.
+#[derive(Debug, Default, Clone, Serialize)]
 pub struct Tracing {
     /// version of sdk
+    #[serde(skip_serializing_if = "Option::is_none")]
     pub sdk: Option<String>,
 }
 
@@ -148,26 +198,151 @@ impl Default for Annotation {
 
 /// A value type which may be used for
 /// filter querying
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, PartialEq, Serialize)]
 #[serde(untagged)]
 pub enum Annotation {
     /// A string value
     String(String),
-    /// A numberic value
-    Number(usize),
+    /// A signed integer value
+    Int(i64),
+    /// An unsigned integer value
+    Uint(u64),
+    /// A floating point value
+    Float(f64),
     /// A boolean value
     Bool(bool),
 }
 
+impl From<String> for Annotation {
+    fn from(value: String) -> Self {
+        Annotation::String(value)
+    }
+}
+
+impl<'a> From<&'a str> for Annotation {
+    fn from(value: &'a str) -> Self {
+        Annotation::String(value.into())
+    }
+}
+
+impl From<bool> for Annotation {
+    fn from(value: bool) -> Self {
+        Annotation::Bool(value)
+    }
+}
+
+macro_rules! annotation_from {
+    ($variant:ident($target:ty): $($source:ty),*) => {
+        $(
+            impl From<$source> for Annotation {
+                fn from(value: $source) -> Self {
+                    Annotation::$variant(value as $target)
+                }
+            }
+        )*
+    };
+}
+
+annotation_from!(Int(i64): i8, i16, i32, i64, isize);
+annotation_from!(Uint(u64): u8, u16, u32, u64, usize);
+annotation_from!(Float(f64): f32, f64);
+
+/// Records a metadata value under a namespace in a lazily created map
+fn put_metadata<N, K, V>(
+    metadata: &mut Option<HashMap<String, HashMap<String, Value>>>,
+    namespace: N,
+    key: K,
+    value: &V,
+) -> Result<()>
+where
+    N: Into<String>,
+    K: Into<String>,
+    V: Serialize + ?Sized,
+{
+    let namespace = namespace.into();
+    if namespace.starts_with(RESERVED_METADATA_PREFIX) {
+        return Err(Error::ReservedNamespace(namespace));
+    }
+    let value = serde_json::to_value(value)?;
+    metadata
+        .get_or_insert_with(HashMap::default)
+        .entry(namespace)
+        .or_default()
+        .insert(key.into(), value);
+    Ok(())
+}
+
+/// Replace a requested sampling decision with the one made downstream
+fn apply_sampling_decision(
+    decision: &mut SamplingDecision,
+    trace_id: &TraceId,
+    response: &Header,
+) {
+    if *decision != SamplingDecision::Requested
+        || response.trace_id.to_string() != trace_id.to_string()
+    {
+        return;
+    }
+    match response.sampling_decision {
+        SamplingDecision::Sampled | SamplingDecision::NotSampled => {
+            *decision = response.sampling_decision.clone()
+        }
+        _ => (),
+    }
+}
+
+/// Ids of a parent's completed subsegments, whether embedded or sent on their own
+fn precursor_ids(
+    subsegments: &[Subsegment],
+    completed_ids: &[SegmentId],
+) -> Option<Vec<String>> {
+    let mut ids: Vec<_> = subsegments
+        .iter()
+        .filter(|subsegment| subsegment.end_time.is_some())
+        .map(|subsegment| subsegment.id.to_string())
+        .collect();
+    for id in completed_ids {
+        let id = id.to_string();
+        if !ids.contains(&id) {
+            ids.push(id);
+        }
+    }
+    if ids.is_empty() {
+        None
+    } else {
+        Some(ids)
+    }
+}
+
+/// Records an annotation in a lazily created map, replacing characters
+/// X-Ray does not allow in keys with `_`
+fn annotate<K, V>(
+    annotations: &mut Option<HashMap<String, Annotation>>,
+    key: K,
+    value: V,
+) where
+    K: Into<String>,
+    V: Into<Annotation>,
+{
+    let mut valid_key = key.into();
+    sanitize_annotation_key(&mut valid_key);
+    annotations
+        .get_or_insert_with(HashMap::default)
+        .insert(valid_key, value.into());
+}
+
 /// Detailed representation of an exception
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 pub struct Exception {
     /// A 64-bit identifier for the exception, unique among segments in the same trace, in 16 hexadecimal digits.
     pub id: String,
     /// The exception message.
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub messages: Option<String>,
+    pub message: Option<String>,
     /// The exception type.
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub type_: Option<String>,
+    /// boolean indicating that the exception was caused by an error returned by a downstream service.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub remote: Option<bool>,
     /// integer indicating the number of stack frames that are omitted from the stack.
@@ -180,25 +355,45 @@ pub struct Exception {
     #[serde(skip_serializing_if = "Option::is_none")]
     pub cause: Option<String>,
     /// array of stackFrame objects.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
     pub stack: Vec<StackFrame>,
 }
 
+impl Exception {
+    /// Create a new exception with a generated id
+    pub fn new<M>(message: M) -> Self
+    where
+        M: Into<String>,
+    {
+        Exception {
+            id: SegmentId::new().to_string(),
+            message: Some(message.into()),
+            type_: None,
+            remote: None,
+            truncated: None,
+            skipped: None,
+            cause: None,
+            stack: Vec::new(),
+        }
+    }
+}
+
 /// A summary of a single operation within a stack trace
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 pub struct StackFrame {
     /// The relative path to the file.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub path: Option<String>,
     /// The line in the file.
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub line: Option<String>,
+    pub line: Option<u32>,
     /// The function or method name.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub label: Option<String>,
 }
 
 /// Represents the cause of an errror
-#[derive(Debug, Serialize)]
+#[derive(Debug, Clone, Serialize)]
 #[serde(untagged)]
 pub enum Cause {
     ///  a 16 character exception ID
@@ -214,34 +409,422 @@ pub enum Cause {
     },
 }
 
+impl Cause {
+    /// Describe an error along with the chain of errors which caused it
+    ///
+    /// Each error in the `source()` chain is recorded as an exception linked to the exception it caused
+    pub fn from_error<E>(err: &E) -> Self
+    where
+        E: StdError + ?Sized,
+    {
+        let mut exceptions = vec![Exception::new(err.to_string())];
+        let mut source = err.source();
+        while let Some(err) = source {
+            exceptions.push(Exception::new(err.to_string()));
+            source = err.source();
+        }
+        Cause::from_exceptions(exceptions)
+    }
+
+    /// Describe a failure along with the chain of failures which caused it
+    ///
+    /// Each failure in the `cause()` chain is recorded as an exception linked to the exception it caused
+    pub fn from_fail<F>(fail: &F) -> Self
+    where
+        F: Fail + ?Sized,
+    {
+        let mut exceptions = vec![exception(fail)];
+        let mut cause = fail.cause();
+        while let Some(fail) = cause {
+            exceptions.push(exception(fail));
+            cause = fail.cause();
+        }
+        Cause::from_exceptions(exceptions)
+    }
+
+    /// Describe a chain of exceptions, starting with the outermost, in the current working directory
+    ///
+    /// Each exception is linked to the exception following it, the exception which caused it
+    pub fn from_exceptions(mut exceptions: Vec<Exception>) -> Self {
+        let ids = exceptions
+            .iter()
+            .map(|exception| exception.id.clone())
+            .collect::<Vec<_>>();
+        for (exception, cause) in exceptions.iter_mut().zip(ids.into_iter().skip(1)) {
+            exception.cause = Some(cause);
+        }
+        Cause::Description {
+            working_directory: env::current_dir()
+                .map(|dir| dir.display().to_string())
+                .unwrap_or_default(),
+            paths: Vec::new(),
+            exceptions,
+        }
+    }
+}
+
+fn exception<F>(fail: &F) -> Exception
+where
+    F: Fail + ?Sized,
+{
+    let mut exception = Exception::new(fail.to_string());
+    exception.type_ = fail.name().map(String::from);
+    exception
+}
+
+impl<'a, E> From<&'a E> for Cause
+where
+    E: StdError + ?Sized,
+{
+    fn from(err: &'a E) -> Self {
+        Cause::from_error(err)
+    }
+}
+
 impl Segment {
     /// Begins a new named segment
     ///
+    /// Names longer than 200 characters are truncated and characters X-Ray does not
+    /// allow are replaced with `_`
+    ///
     /// A segment's name should match the domain name or logical name of the service that generates the segment. However, this is not enforced. Any application that has permission to PutTraceSegments can send segments with any name.
     pub fn begin<N>(name: N) -> Self
     where
         N: Into<String>,
     {
         let mut valid_name = name.into();
-        if valid_name.len() > 200 {
-            valid_name = valid_name[..200].into();
-        }
+        sanitize_name(&mut valid_name);
         Segment {
             name: valid_name,
             ..Segment::default()
         }
     }
 
+    /// Continue the trace described by an incoming tracing header
+    ///
+    /// The segment joins the header's trace as a child of its parent and keeps its
+    /// sampling decision, recording nothing if the trace was not sampled. When the caller
+    /// requested a decision with `Sampled=?` the trace is sampled, and the decision is
+    /// returned to the caller with `response_header`. A fresh trace is started when the
+    /// header is absent or its trace ID is malformed
+    ///
+    /// Use `from_header_with` to make the requested decision with a sampler
+    pub fn from_header<'a, N, H>(
+        name: N,
+        header: H,
+    ) -> Self
+    where
+        N: Into<String>,
+        H: Into<Option<&'a Header>>,
+    {
+        Segment::from_header_with(name, header, || true)
+    }
+
+    /// Continue the trace described by an incoming tracing header as `from_header` does,
+    /// calling `decide` to sample the trace when the caller requested a decision with `Sampled=?`
+    ///
+    /// The trace is recorded if `decide` returns true, and `response_header` returns
+    /// the decision to the caller either way
+    pub fn from_header_with<'a, N, H, F>(
+        name: N,
+        header: H,
+        decide: F,
+    ) -> Self
+    where
+        N: Into<String>,
+        H: Into<Option<&'a Header>>,
+        F: FnOnce() -> bool,
+    {
+        match header.into() {
+            Some(header) if header.trace_id.is_valid() => {
+                let sampled = match header.sampling_decision {
+                    SamplingDecision::NotSampled => false,
+                    SamplingDecision::Requested => decide(),
+                    _ => true,
+                };
+                if !sampled {
+                    return Segment::unsampled(header);
+                }
+                let mut segment = Segment::begin(name);
+                segment.trace_id = header.trace_id.clone();
+                segment.parent_id = header
+                    .parent_id
+                    .clone()
+                    .filter(|parent_id| parent_id.is_valid());
+                segment.sampling_decision = match header.sampling_decision {
+                    SamplingDecision::Requested => SamplingDecision::Sampled,
+                    ref decision => decision.clone(),
+                };
+                segment.lineage = header.lineage();
+                segment
+            }
+            _ => Segment::begin(name),
+        }
+    }
+
+    /// Return a segment of an unsampled trace which only carries the header's ids for propagation
+    ///
+    /// The segment stands in for the caller's parent, so no id is generated unless the
+    /// header has no valid parent, and no time is recorded
+    fn unsampled(header: &Header) -> Self {
+        let parent_id = header
+            .parent_id
+            .clone()
+            .filter(|parent_id| parent_id.is_valid());
+        Segment {
+            trace_id: header.trace_id.clone(),
+            id: parent_id.clone().unwrap_or_else(SegmentId::new),
+            name: String::new(),
+            start_time: Seconds(0.0),
+            sampling_decision: SamplingDecision::NotSampled,
+            lineage: header.lineage(),
+            facade: false,
+            end_time: None,
+            in_progress: false,
+            parent_id,
+            fault: false,
+            error: false,
+            throttle: false,
+            cause: None,
+            origin: None,
+            user: None,
+            resource_arn: None,
+            http: None,
+            annotations: None,
+            metadata: None,
+            aws: None,
+            service: None,
+            subsegments: Vec::new(),
+            completed_ids: Vec::new(),
+        }
+    }
+
+    /// Return the chain of function invocations this segment's trace went through
+    pub fn lineage(&self) -> Option<&Lineage> {
+        self.lineage.as_ref()
+    }
+
+    /// Count an invocation of the function identified by `hash` in the lineage propagated
+    /// to downstream calls
+    ///
+    /// Lambda functions call this so recursive invocations can be detected, see `RecursionGuard`
+    pub fn increment_lineage(
+        &mut self,
+        hash: &str,
+    ) -> &mut Self {
+        self.lineage
+            .get_or_insert_with(Lineage::default)
+            .increment(hash);
+        self
+    }
+
+    /// Return true if this segment stands in for a segment recorded by AWS Lambda
+    ///
+    /// Facades are never sent. Clients send their embedded subsegments as
+    /// independent subsegments instead, see `xray::lambda::facade`
+    pub fn is_facade(&self) -> bool {
+        self.facade
+    }
+
+    /// Return false if this segment's trace was not sampled
+    ///
+    /// Unsampled segments only carry ids for propagation. Recording data on them
+    /// does nothing and clients never send them
+    pub fn is_sampled(&self) -> bool {
+        self.sampling_decision != SamplingDecision::NotSampled
+    }
+
+    /// Return the tracing header to send in the response to the caller, which carries
+    /// this service's sampling decision
+    pub fn response_header(&self) -> Header {
+        let mut header = Header::new(self.trace_id.clone());
+        header.with_sampling_decision(if self.is_sampled() {
+            SamplingDecision::Sampled
+        } else {
+            SamplingDecision::NotSampled
+        });
+        header
+    }
+
+    /// Apply the sampling decision a downstream service returned in its response header
+    ///
+    /// Only segments of traces with a requested decision are updated, and only by
+    /// headers of the same trace
+    pub fn apply_sampling_decision(
+        &mut self,
+        response: &Header,
+    ) -> &mut Self {
+        apply_sampling_decision(&mut self.sampling_decision, &self.trace_id, response);
+        self
+    }
+
+    /// Return the trace this segment belongs to
+    pub fn trace_id(&self) -> &TraceId {
+        &self.trace_id
+    }
+
+    /// Return the id of this segment
+    pub fn id(&self) -> &SegmentId {
+        &self.id
+    }
+
+    /// Return the name of this segment
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Return the time this segment was started
+    pub fn start_time(&self) -> &Seconds {
+        &self.start_time
+    }
+
+    /// Return the time this segment was ended, if it has ended
+    pub fn end_time(&self) -> Option<&Seconds> {
+        self.end_time.as_ref()
+    }
+
     /// End the segment by assigning its end_time
     pub fn end(&mut self) -> &mut Self {
         self.end_time = Some(Seconds::now());
         self.in_progress = false;
         self
     }
+
+    /// Begin a new subsegment of this segment
+    ///
+    /// The subsegment may be sent on its own or embedded in this segment with `end_subsegment`.
+    /// Its `precursor_ids` are set to the ids of subsegments already completed in this segment,
+    /// including those sent on their own and recorded with `complete_child`
+    pub fn begin_subsegment<N>(
+        &self,
+        name: N,
+    ) -> Subsegment
+    where
+        N: Into<String>,
+    {
+        if !self.is_sampled() {
+            return Subsegment::unsampled(
+                Some(self.trace_id.clone()),
+                self.id.clone(),
+                self.lineage.clone(),
+            );
+        }
+        Subsegment {
+            precursor_ids: precursor_ids(&self.subsegments, &self.completed_ids),
+            sampling_decision: self.sampling_decision.clone(),
+            lineage: self.lineage.clone(),
+            ..Subsegment::begin(self.trace_id.clone(), Some(self.id.clone()), name)
+        }
+    }
+
+    /// End a subsegment and embed it in this segment
+    pub fn end_subsegment(
+        &mut self,
+        mut subsegment: Subsegment,
+    ) -> &mut Self {
+        if subsegment.end_time.is_none() {
+            subsegment.end();
+        }
+        self.add_subsegment(subsegment)
+    }
+
+    /// Embed a subsegment in this segment as it is, for example a subsegment still in progress
+    pub fn add_subsegment(
+        &mut self,
+        subsegment: Subsegment,
+    ) -> &mut Self {
+        if self.is_sampled() {
+            self.subsegments.push(subsegment.embedded());
+        }
+        self
+    }
+
+    /// Record that a subsegment of this segment completed and was sent on its own,
+    /// so subsegments begun afterwards list it among their `precursor_ids`
+    pub fn complete_child(
+        &mut self,
+        id: &SegmentId,
+    ) -> &mut Self {
+        if self.is_sampled() && !self.completed_ids.contains(id) {
+            self.completed_ids.push(id.clone());
+        }
+        self
+    }
+
+    /// Record a key-value pair X-Ray will index for filter expressions
+    ///
+    /// Keys may only contain alphanumeric characters and underscores. Other characters are replaced with `_`
+    pub fn annotate<K, V>(
+        &mut self,
+        key: K,
+        value: V,
+    ) -> &mut Self
+    where
+        K: Into<String>,
+        V: Into<Annotation>,
+    {
+        if self.is_sampled() {
+            annotate(&mut self.annotations, key, value);
+        }
+        self
+    }
+
+    /// Record a value under a metadata namespace. Metadata is stored but not indexed for search
+    ///
+    /// The `default` namespace is used by convention. Namespaces beginning with `AWS.` are reserved
+    /// and values which fail to serialize are not recorded.
+    pub fn put_metadata<N, K, V>(
+        &mut self,
+        namespace: N,
+        key: K,
+        value: &V,
+    ) -> Result<&mut Self>
+    where
+        N: Into<String>,
+        K: Into<String>,
+        V: Serialize + ?Sized,
+    {
+        if self.is_sampled() {
+            put_metadata(&mut self.metadata, namespace, key, value)?;
+        }
+        Ok(self)
+    }
+
+    /// Record the cause of a server side failure and mark this segment as a fault
+    ///
+    /// Errors can be converted into a `Cause` directly, failures with `Cause::from_fail`
+    pub fn record_fault<C>(
+        &mut self,
+        cause: C,
+    ) -> &mut Self
+    where
+        C: Into<Cause>,
+    {
+        if self.is_sampled() {
+            self.fault = true;
+            self.cause = Some(cause.into());
+        }
+        self
+    }
+
+    /// Record the cause of a client side failure and mark this segment as an error
+    pub fn record_error<C>(
+        &mut self,
+        cause: C,
+    ) -> &mut Self
+    where
+        C: Into<Cause>,
+    {
+        if self.is_sampled() {
+            self.error = true;
+            self.cause = Some(cause.into());
+        }
+        self
+    }
 }
 
 /// Describes an http request/response cycle
-#[derive(Debug, Serialize, Deserialize, Default)]
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct Http {
     /// Information about a request
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -252,7 +835,7 @@ pub struct Http {
 }
 
 ///  Information about a request.
-#[derive(Debug, Serialize, Deserialize, Default)]
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct Request {
     /// The request method. For example, GET.
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -268,25 +851,28 @@ pub struct Request {
     pub user_agent: Option<String>,
     /// (segments only) boolean indicating that the client_ip was read from an X-Forwarded-For header and is not reliable as it could have been forged.
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub x_forwarded_for: Option<String>,
+    pub x_forwarded_for: Option<bool>,
     /// (subsegments only) boolean indicating that the downstream call is to another traced service. If this field is set to true, X-Ray considers the trace to be broken until the downstream service uploads a segment with a parent_id that matches the id of the subsegment that contains this block.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub traced: Option<bool>,
 }
 
 ///  Information about a response.
-#[derive(Debug, Serialize, Deserialize, Default)]
+#[derive(Debug, Clone, Serialize, Deserialize, Default)]
 pub struct Response {
     /// number indicating the HTTP status of the response.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub status: Option<u16>,
-    /// number indicating the length of the response body in bytes.
+    /// number indicating the length of the response body in bytes, or -1 when unknown.
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub content_length: Option<u64>,
+    pub content_length: Option<i64>,
 }
 
 impl Subsegment {
     /// Create a new subsegment
+    ///
+    /// Names longer than 200 characters are truncated and characters X-Ray does not
+    /// allow are replaced with `_`
     pub fn begin<N>(
         trace_id: TraceId,
         parent_id: Option<SegmentId>,
@@ -296,9 +882,7 @@ impl Subsegment {
         N: Into<String>,
     {
         let mut valid_name = name.into();
-        if valid_name.len() > 200 {
-            valid_name = valid_name[..200].into();
-        }
+        sanitize_name(&mut valid_name);
         Subsegment {
             name: valid_name,
             trace_id: Some(trace_id),
@@ -309,16 +893,239 @@ impl Subsegment {
         }
     }
 
+    /// Return the tracing header to send with a downstream call made by this subsegment
+    ///
+    /// Returns `None` for embedded subsegments, which have no `trace_id`
+    pub fn header(&self) -> Option<Header> {
+        let trace_id = self.trace_id.clone()?;
+        let mut header = Header::new(trace_id);
+        header
+            .with_parent_id(self.id.clone())
+            .with_sampling_decision(match self.sampling_decision {
+                SamplingDecision::NotSampled => SamplingDecision::NotSampled,
+                SamplingDecision::Requested => SamplingDecision::Requested,
+                _ => SamplingDecision::Sampled,
+            });
+        if let Some(lineage) = &self.lineage {
+            header.with_lineage(lineage);
+        }
+        Some(header)
+    }
+
+    /// Apply the sampling decision a downstream service returned in its response header
+    ///
+    /// Only subsegments of traces with a requested decision are updated, and only by
+    /// headers of the same trace
+    pub fn apply_sampling_decision(
+        &mut self,
+        response: &Header,
+    ) -> &mut Self {
+        if let Some(trace_id) = &self.trace_id {
+            apply_sampling_decision(&mut self.sampling_decision, trace_id, response);
+        }
+        self
+    }
+
+    /// Return false if this subsegment's trace was not sampled
+    ///
+    /// Unsampled subsegments only carry ids for propagation. Recording data on them
+    /// does nothing and clients never send them
+    pub fn is_sampled(&self) -> bool {
+        self.sampling_decision != SamplingDecision::NotSampled
+    }
+
+    /// Return the id of this subsegment
+    pub fn id(&self) -> &SegmentId {
+        &self.id
+    }
+
+    /// Return the name of this subsegment
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Return the time this subsegment was started
+    pub fn start_time(&self) -> &Seconds {
+        &self.start_time
+    }
+
+    /// Return the time this subsegment was ended, if it has ended
+    pub fn end_time(&self) -> Option<&Seconds> {
+        self.end_time.as_ref()
+    }
+
+    /// Return a subsegment of an unsampled trace which only carries ids for propagation
+    fn unsampled(
+        trace_id: Option<TraceId>,
+        parent_id: SegmentId,
+        lineage: Option<Lineage>,
+    ) -> Self {
+        Subsegment {
+            trace_id,
+            parent_id: Some(parent_id),
+            sampling_decision: SamplingDecision::NotSampled,
+            lineage,
+            ..Subsegment::default()
+        }
+    }
+
     /// End the subsegment by assigning its end_time
     pub fn end(&mut self) -> &mut Self {
         self.end_time = Some(Seconds::now());
         self.in_progress = false;
         self
     }
+
+    /// Begin a new subsegment of this subsegment
+    ///
+    /// The subsegment may be sent on its own or embedded in this subsegment with `end_subsegment`.
+    /// Its `precursor_ids` are set to the ids of subsegments already completed in this subsegment,
+    /// including those sent on their own and recorded with `complete_child`
+    pub fn begin_subsegment<N>(
+        &self,
+        name: N,
+    ) -> Subsegment
+    where
+        N: Into<String>,
+    {
+        if !self.is_sampled() {
+            return Subsegment::unsampled(
+                self.trace_id.clone(),
+                self.id.clone(),
+                self.lineage.clone(),
+            );
+        }
+        let mut valid_name = name.into();
+        sanitize_name(&mut valid_name);
+        Subsegment {
+            name: valid_name,
+            trace_id: self.trace_id.clone(),
+            parent_id: Some(self.id.clone()),
+            type_: "subsegment".into(),
+            in_progress: true,
+            precursor_ids: precursor_ids(&self.subsegments, &self.completed_ids),
+            sampling_decision: self.sampling_decision.clone(),
+            lineage: self.lineage.clone(),
+            ..Subsegment::default()
+        }
+    }
+
+    /// End a subsegment and embed it in this subsegment
+    pub fn end_subsegment(
+        &mut self,
+        mut subsegment: Subsegment,
+    ) -> &mut Self {
+        if subsegment.end_time.is_none() {
+            subsegment.end();
+        }
+        self.add_subsegment(subsegment)
+    }
+
+    /// Embed a subsegment in this subsegment as it is, for example a subsegment still in progress
+    pub fn add_subsegment(
+        &mut self,
+        subsegment: Subsegment,
+    ) -> &mut Self {
+        if self.is_sampled() {
+            self.subsegments.push(subsegment.embedded());
+        }
+        self
+    }
+
+    /// Record that a subsegment of this subsegment completed and was sent on its own,
+    /// so subsegments begun afterwards list it among their `precursor_ids`
+    pub fn complete_child(
+        &mut self,
+        id: &SegmentId,
+    ) -> &mut Self {
+        if self.is_sampled() && !self.completed_ids.contains(id) {
+            self.completed_ids.push(id.clone());
+        }
+        self
+    }
+
+    /// Strip the fields only independently sent subsegments need
+    fn embedded(mut self) -> Self {
+        self.trace_id = None;
+        self.parent_id = None;
+        self.type_.clear();
+        self
+    }
+
+    /// Record a key-value pair X-Ray will index for filter expressions
+    ///
+    /// Keys may only contain alphanumeric characters and underscores. Other characters are replaced with `_`
+    pub fn annotate<K, V>(
+        &mut self,
+        key: K,
+        value: V,
+    ) -> &mut Self
+    where
+        K: Into<String>,
+        V: Into<Annotation>,
+    {
+        if self.is_sampled() {
+            annotate(&mut self.annotations, key, value);
+        }
+        self
+    }
+
+    /// Record a value under a metadata namespace. Metadata is stored but not indexed for search
+    ///
+    /// The `default` namespace is used by convention. Namespaces beginning with `AWS.` are reserved
+    /// and values which fail to serialize are not recorded.
+    pub fn put_metadata<N, K, V>(
+        &mut self,
+        namespace: N,
+        key: K,
+        value: &V,
+    ) -> Result<&mut Self>
+    where
+        N: Into<String>,
+        K: Into<String>,
+        V: Serialize + ?Sized,
+    {
+        if self.is_sampled() {
+            put_metadata(&mut self.metadata, namespace, key, value)?;
+        }
+        Ok(self)
+    }
+
+    /// Record the cause of a server side failure and mark this subsegment as a fault
+    ///
+    /// Errors can be converted into a `Cause` directly, failures with `Cause::from_fail`
+    pub fn record_fault<C>(
+        &mut self,
+        cause: C,
+    ) -> &mut Self
+    where
+        C: Into<Cause>,
+    {
+        if self.is_sampled() {
+            self.fault = true;
+            self.cause = Some(cause.into());
+        }
+        self
+    }
+
+    /// Record the cause of a client side failure and mark this subsegment as an error
+    pub fn record_error<C>(
+        &mut self,
+        cause: C,
+    ) -> &mut Self
+    where
+        C: Into<Cause>,
+    {
+        if self.is_sampled() {
+            self.error = true;
+            self.cause = Some(cause.into());
+        }
+        self
+    }
 }
 
 /// Record information about the AWS services and resources that your application accesses. X-Ray uses this information to create inferred segments that represent the downstream services in your service map.
-#[derive(Debug, Default, Serialize)]
+#[derive(Debug, Default, Clone, Serialize)]
 pub struct Subsegment {
     /// The logical name of the subsegment. For downstream calls, name the subsegment after the resource or service called. For custom subsegments, name the subsegment after the code that it instruments (e.g., a function name).
     pub(crate) name: String,
@@ -329,6 +1136,12 @@ pub struct Subsegment {
     /// number that is the time the subsegment was closed. For example, 1480615200.090 or 1.480615200090E9. Specify an end_time or in_progress.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub(crate) end_time: Option<Seconds>,
+    /// The sampling decision propagated to downstream calls
+    #[serde(skip)]
+    pub(crate) sampling_decision: SamplingDecision,
+    /// The chain of function invocations propagated to downstream calls
+    #[serde(skip)]
+    pub(crate) lineage: Option<Lineage>,
     /// Trace ID of the subsegment's parent segment. Required only if sending a subsegment separately.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub trace_id: Option<TraceId>,
@@ -345,14 +1158,11 @@ pub struct Subsegment {
     #[serde(skip_serializing_if = "Not::not")]
     pub error: bool,
     ///  boolean indicating that a request was throttled (response status code was 429 Too Many Requests).
-    #[serde(skip_serializing_if = "Not::not")]
+    #[serde(rename = "throttle", skip_serializing_if = "Not::not")]
     pub throttled: bool,
     /// aws for AWS SDK calls; remote for other downstream calls.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub namespace: Option<String>,
-    ///
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub traced: Option<bool>,
     /// array of subsegment IDs that identifies subsegments with the same parent that completed prior to this subsegment.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub precursor_ids: Option<Vec<String>>,
@@ -362,15 +1172,18 @@ pub struct Subsegment {
     /// annotations object with key-value pairs that you want X-Ray to index for search.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub annotations: Option<HashMap<String, Annotation>>,
-    /// metadata object with any additional data that you want to store in the segment.
+    /// metadata object with any additional data that you want to store in the segment, keyed by namespace.
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub metadata: Option<HashMap<String, Value>>,
+    pub metadata: Option<HashMap<String, HashMap<String, Value>>>,
     /// subsegment. Required only if sending a subsegment separately.
-    #[serde(rename = "type")]
+    #[serde(rename = "type", skip_serializing_if = "String::is_empty")]
     pub type_: String,
     /// array of subsegment objects.
     #[serde(skip_serializing_if = "Vec::is_empty")]
     pub subsegments: Vec<Subsegment>,
+    /// Ids of completed subsegments which were sent on their own rather than embedded
+    #[serde(skip)]
+    pub(crate) completed_ids: Vec<SegmentId>,
     ///  http object with information about an outgoing HTTP call.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub http: Option<Http>,
@@ -383,7 +1196,7 @@ pub struct Subsegment {
 }
 
 /// Information about an AWS operation
-#[derive(Debug, Default, Serialize)]
+#[derive(Debug, Default, Clone, Serialize)]
 pub struct AwsOperation {
     /// The name of the API action invoked against an AWS service or resource.
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -403,10 +1216,13 @@ pub struct AwsOperation {
     /// For operations on a DynamoDB table, the name of the table.
     #[serde(skip_serializing_if = "Option::is_none")]
     pub table_name: Option<String>,
+    /// Other request and response parameters recorded alongside these fields, such as `consistent_read` or `consumed_capacity`
+    #[serde(flatten)]
+    pub parameters: HashMap<String, Value>,
 }
 
 /// Information about a SQL operation
-#[derive(Debug, Default, Serialize)]
+#[derive(Debug, Default, Clone, Serialize)]
 pub struct Sql {
     /// For SQL Server or other database connections that don't use URL connection strings, record the connection string, excluding passwords.
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -436,7 +1252,11 @@ pub struct Sql {
 
 #[cfg(test)]
 mod tests {
-    use super::{Seconds, Segment, SegmentId, Subsegment, TraceId};
+    use super::*;
+    use failure::Fail;
+    use serde::{Serialize, Serializer};
+    use serde_json::json;
+    use std::{error::Error as StdError, fmt, io};
 
     #[test]
     fn segments_begin_with_names_with_a_max_len() {
@@ -467,6 +1287,239 @@ mod tests {
         );
     }
 
+    #[test]
+    fn segments_begin_with_non_ascii_names_truncated_on_char_boundaries() {
+        assert_eq!(Segment::begin("café-service").name, "café-service");
+        assert_eq!(Segment::begin("サービス").name, "サービス");
+        let name = Segment::begin("é".repeat(201)).name;
+        assert_eq!(name.chars().count(), 200);
+        assert_eq!(name, "é".repeat(200));
+    }
+
+    #[test]
+    fn subsegments_begin_with_non_ascii_names_truncated_on_char_boundaries() {
+        let name = Subsegment::begin(TraceId::default(), None, "日本".repeat(101)).name;
+        assert_eq!(name.chars().count(), 200);
+        assert_eq!(name, "日本".repeat(100));
+    }
+
+    #[test]
+    fn begin_replaces_disallowed_name_characters() {
+        assert_eq!(
+            Segment::begin("api.example.com/users?id=1").name,
+            "api.example.com/users_id=1"
+        );
+        assert_eq!(
+            Subsegment::begin(TraceId::default(), None, "select * from users;").name,
+            "select _ from users_"
+        );
+    }
+
+    #[test]
+    fn annotations_convert_from_primitives() {
+        assert_eq!(Annotation::from("str"), Annotation::String("str".into()));
+        assert_eq!(Annotation::from(true), Annotation::Bool(true));
+        assert_eq!(Annotation::from(-3i32), Annotation::Int(-3));
+        assert_eq!(Annotation::from(3usize), Annotation::Uint(3));
+        assert_eq!(Annotation::from(12.5f64), Annotation::Float(12.5));
+    }
+
+    #[test]
+    fn annotations_serialize() {
+        assert_eq!(
+            serde_json::to_string(&vec![
+                Annotation::from(-1),
+                Annotation::from(1u64),
+                Annotation::from(1.5),
+                Annotation::from("one"),
+                Annotation::from(false)
+            ])
+            .expect("failed to serialize"),
+            r#"[-1,1,1.5,"one",false]"#
+        )
+    }
+
+    #[test]
+    fn annotate_sanitizes_keys() {
+        let mut segment = Segment::begin("annotated");
+        segment
+            .annotate("latency-ms", 12.5)
+            .annotate("user_id", "abc");
+        let annotations = segment.annotations.unwrap_or_default();
+        assert_eq!(
+            annotations.get("latency_ms"),
+            Some(&Annotation::Float(12.5))
+        );
+        assert_eq!(
+            annotations.get("user_id"),
+            Some(&Annotation::String("abc".into()))
+        );
+    }
+
+    #[test]
+    fn subsegments_annotate() {
+        let mut subsegment = Subsegment::begin(TraceId::default(), None, "annotated");
+        subsegment.annotate("retries", -1);
+        assert_eq!(
+            subsegment.annotations.unwrap_or_default().get("retries"),
+            Some(&Annotation::Int(-1))
+        );
+    }
+
+    #[test]
+    fn put_metadata_namespaces_values() {
+        let mut segment = Segment {
+            name: "Scorekeep".into(),
+            id: SegmentId::Rendered("70de5b6f19ff9a0a".into()),
+            start_time: Seconds(1_478_293_361.271),
+            trace_id: TraceId::Rendered("1-581cf771-a006649127e371903a2de979".into()),
+            end_time: Some(Seconds(1_478_293_361.449)),
+            ..Segment::default()
+        };
+        segment
+            .put_metadata("default", "retries", &2)
+            .and_then(|segment| segment.put_metadata("debug", "test", &["a", "b"]))
+            .expect("failed to put metadata");
+        assert_eq!(
+            serde_json::to_value(&segment).expect("failed to serialize")["metadata"],
+            serde_json::json!({
+                "default": { "retries": 2 },
+                "debug": { "test": ["a", "b"] }
+            })
+        );
+    }
+
+    #[test]
+    fn put_metadata_rejects_reserved_namespaces() {
+        let mut subsegment = Subsegment::begin(TraceId::default(), None, "reserved");
+        match subsegment.put_metadata("AWS.lambda", "key", "value") {
+            Err(Error::ReservedNamespace(namespace)) => assert_eq!(namespace, "AWS.lambda"),
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+        assert!(subsegment.metadata.is_none());
+    }
+
+    #[test]
+    fn put_metadata_fails_on_unserializable_values() {
+        struct Unserializable;
+        impl Serialize for Unserializable {
+            fn serialize<S>(
+                &self,
+                _: S,
+            ) -> std::result::Result<S::Ok, S::Error>
+            where
+                S: Serializer,
+            {
+                Err(serde::ser::Error::custom("unserializable"))
+            }
+        }
+        let mut segment = Segment::begin("unserializable");
+        match segment.put_metadata("default", "key", &Unserializable) {
+            Err(Error::Json(_)) => (),
+            other => panic!("unexpected result {:?}", other.map(|_| ())),
+        }
+        assert!(segment.metadata.is_none());
+    }
+
+    #[derive(Debug)]
+    struct Wrapped(io::Error);
+
+    impl fmt::Display for Wrapped {
+        fn fmt(
+            &self,
+            f: &mut fmt::Formatter,
+        ) -> fmt::Result {
+            write!(f, "wrapped")
+        }
+    }
+
+    impl StdError for Wrapped {
+        fn source(&self) -> Option<&(dyn StdError + 'static)> {
+            Some(&self.0)
+        }
+    }
+
+    #[derive(Debug)]
+    struct Outer(io::Error);
+
+    impl fmt::Display for Outer {
+        fn fmt(
+            &self,
+            f: &mut fmt::Formatter,
+        ) -> fmt::Result {
+            write!(f, "outer failure")
+        }
+    }
+
+    impl Fail for Outer {
+        fn name(&self) -> Option<&str> {
+            Some("Outer")
+        }
+
+        fn cause(&self) -> Option<&dyn Fail> {
+            Some(&self.0)
+        }
+    }
+
+    fn messages_and_causes(cause: &Cause) -> Vec<(Option<String>, bool)> {
+        match cause {
+            Cause::Description { exceptions, .. } => {
+                for pair in exceptions.windows(2) {
+                    assert_eq!(pair[0].cause.as_ref(), Some(&pair[1].id));
+                }
+                assert!(exceptions.iter().all(|exception| exception.id.len() == 16));
+                exceptions
+                    .iter()
+                    .map(|exception| (exception.message.clone(), exception.cause.is_some()))
+                    .collect()
+            }
+            other => panic!("unexpected cause {:?}", other),
+        }
+    }
+
+    #[test]
+    fn causes_from_error_chains() {
+        let err = Wrapped(io::Error::new(io::ErrorKind::NotFound, "io"));
+        let cause = Cause::from_error(&err);
+        assert_eq!(
+            messages_and_causes(&cause),
+            vec![(Some("wrapped".into()), true), (Some("io".into()), false)]
+        );
+        if let Cause::Description {
+            working_directory, ..
+        } = cause
+        {
+            assert!(!working_directory.is_empty());
+        }
+    }
+
+    #[test]
+    fn causes_from_fail_chains() {
+        let fail = Outer(io::Error::new(io::ErrorKind::NotFound, "io"));
+        let cause = Cause::from_fail(&fail);
+        if let Cause::Description { exceptions, .. } = &cause {
+            assert_eq!(exceptions[0].type_.as_deref(), Some("Outer"));
+        }
+        assert_eq!(
+            messages_and_causes(&cause),
+            vec![
+                (Some("outer failure".into()), true),
+                (Some("io".into()), false)
+            ]
+        );
+    }
+
+    #[test]
+    fn record_fault_and_error() {
+        let err = io::Error::new(io::ErrorKind::NotFound, "io");
+        let mut segment = Segment::begin("faulted");
+        segment.record_fault(&err);
+        assert!(segment.fault && !segment.error && segment.cause.is_some());
+        let mut subsegment = Subsegment::begin(TraceId::default(), None, "errored");
+        subsegment.record_error(&err as &dyn StdError);
+        assert!(subsegment.error && !subsegment.fault && subsegment.cause.is_some());
+    }
+
     #[test]
     fn segments_serialize() {
         assert_eq!(
@@ -482,4 +1535,823 @@ mod tests {
             .expect("failed to serialize")
         )
     }
+
+    fn assert_golden<D>(
+        document: &D,
+        expected: Value,
+    ) where
+        D: Serialize,
+    {
+        assert_eq!(
+            serde_json::to_value(document).expect("failed to serialize"),
+            expected
+        )
+    }
+
+    fn independent(
+        name: &str,
+        id: &str,
+        start_time: f64,
+        end_time: f64,
+    ) -> Subsegment {
+        Subsegment {
+            name: name.into(),
+            id: SegmentId::Rendered(id.into()),
+            start_time: Seconds(start_time),
+            end_time: Some(Seconds(end_time)),
+            trace_id: Some(TraceId::Rendered(
+                "1-581cf771-a006649127e371903a2de979".into(),
+            )),
+            parent_id: Some(SegmentId::Rendered("defdfd9912dc5a56".into())),
+            type_: "subsegment".into(),
+            ..Subsegment::default()
+        }
+    }
+
+    #[test]
+    fn subsegments_end_embedded_in_segments() {
+        let mut segment = Segment::begin("parent");
+        let mut child = segment.begin_subsegment("child");
+        assert_eq!(child.trace_id, Some(segment.trace_id.clone()));
+        assert_eq!(child.parent_id, Some(segment.id.clone()));
+        let grandchild = child.begin_subsegment("grandchild");
+        assert_eq!(grandchild.parent_id, Some(child.id.clone()));
+        child.end_subsegment(grandchild);
+        segment.end_subsegment(child);
+        let child = &segment.subsegments[0];
+        assert!(child.end_time.is_some() && !child.in_progress);
+        assert!(child.trace_id.is_none() && child.parent_id.is_none() && child.type_.is_empty());
+        assert!(child.subsegments[0].end_time.is_some());
+    }
+
+    #[test]
+    fn subsegments_record_completed_siblings_as_precursors() {
+        let mut segment = Segment::begin("parent");
+        let first = segment.begin_subsegment("first");
+        assert!(first.precursor_ids.is_none());
+        let first_id = first.id.to_string();
+        segment.end_subsegment(first);
+        let pending = segment.begin_subsegment("pending");
+        segment.add_subsegment(pending);
+        let second = segment.begin_subsegment("second");
+        assert_eq!(second.precursor_ids, Some(vec![first_id.clone()]));
+        let second_id = second.id.to_string();
+        segment.end_subsegment(second);
+        let third = segment.begin_subsegment("third");
+        assert_eq!(third.precursor_ids, Some(vec![first_id, second_id]));
+
+        let mut parent = segment.begin_subsegment("nested");
+        let child = parent.begin_subsegment("child");
+        let child_id = child.id.to_string();
+        parent.end_subsegment(child);
+        assert_eq!(
+            parent.begin_subsegment("next").precursor_ids,
+            Some(vec![child_id])
+        );
+    }
+
+    #[test]
+    fn subsegments_record_siblings_sent_on_their_own_as_precursors() {
+        let mut segment = Segment::begin("parent");
+        let embedded = segment.begin_subsegment("embedded");
+        let embedded_id = embedded.id.clone();
+        segment.end_subsegment(embedded);
+        let sent = segment.begin_subsegment("sent");
+        segment.complete_child(&sent.id).complete_child(&sent.id);
+        segment.complete_child(&embedded_id);
+        assert_eq!(
+            segment.begin_subsegment("next").precursor_ids,
+            Some(vec![embedded_id.to_string(), sent.id.to_string()])
+        );
+
+        let mut parent = segment.begin_subsegment("nested");
+        parent.complete_child(&sent.id);
+        assert_eq!(
+            parent.begin_subsegment("next").precursor_ids,
+            Some(vec![sent.id.to_string()])
+        );
+    }
+
+    #[test]
+    fn segments_continue_traces_from_headers() {
+        let header = "Root=1-5759e988-bd862e3fe1be46a994272793;Parent=53995c3f42cd8ad8;Sampled=0"
+            .parse::<Header>()
+            .expect("invalid header");
+        let segment = Segment::from_header("service", &header);
+        assert_eq!(
+            segment.trace_id.to_string(),
+            "1-5759e988-bd862e3fe1be46a994272793"
+        );
+        assert_eq!(
+            segment.parent_id.as_ref().map(ToString::to_string),
+            Some("53995c3f42cd8ad8".into())
+        );
+        assert_eq!(segment.sampling_decision, SamplingDecision::NotSampled);
+
+        let subsegment = segment.begin_subsegment("downstream");
+        assert_eq!(
+            subsegment.header().map(|header| header.to_string()),
+            Some(format!(
+                "Root=1-5759e988-bd862e3fe1be46a994272793;Parent={};Sampled=0",
+                subsegment.id
+            ))
+        );
+    }
+
+    #[test]
+    fn segments_start_fresh_traces_without_valid_headers() {
+        let header = "Root=bogus;Parent=53995c3f42cd8ad8;Sampled=1"
+            .parse::<Header>()
+            .expect("invalid header");
+        let segment = Segment::from_header("service", &header);
+        assert!(segment.trace_id.is_valid());
+        assert_ne!(segment.trace_id.to_string(), "bogus");
+        assert!(segment.parent_id.is_none());
+        assert!(Segment::from_header("service", None).parent_id.is_none());
+
+        let header = "Root=1-5759e988-bd862e3fe1be46a994272793;Parent=bogus"
+            .parse::<Header>()
+            .expect("invalid header");
+        let segment = Segment::from_header("service", &header);
+        assert!(segment.parent_id.is_none());
+        assert_eq!(
+            segment
+                .begin_subsegment("downstream")
+                .header()
+                .map(|header| header.sampling_decision),
+            Some(SamplingDecision::Sampled)
+        );
+    }
+
+    #[test]
+    fn unsampled_segments_record_nothing() {
+        let header = "Root=1-5759e988-bd862e3fe1be46a994272793;Parent=53995c3f42cd8ad8;Sampled=0"
+            .parse::<Header>()
+            .expect("invalid header");
+        let mut segment = Segment::from_header("service", &header);
+        assert!(!segment.is_sampled());
+        assert!(segment.name.is_empty());
+        assert_eq!(segment.id.to_string(), "53995c3f42cd8ad8");
+        assert_eq!(segment.start_time, Seconds(0.0));
+        let mut subsegment = segment.begin_subsegment("downstream");
+        assert!(!subsegment.is_sampled());
+        assert!(subsegment.name.is_empty());
+        assert_eq!(subsegment.parent_id.as_ref(), Some(&segment.id));
+        subsegment
+            .annotate("key", "value")
+            .record_fault(&io::Error::new(io::ErrorKind::NotFound, "io"));
+        subsegment
+            .put_metadata("default", "key", "value")
+            .expect("failed to put metadata");
+        assert!(subsegment.annotations.is_none() && subsegment.metadata.is_none());
+        assert!(!subsegment.fault && subsegment.cause.is_none());
+        segment.end_subsegment(subsegment);
+        assert!(segment.subsegments.is_empty());
+    }
+
+    #[test]
+    fn requested_sampling_decisions_are_made_by_the_sampler() {
+        let header = "Root=1-5759e988-bd862e3fe1be46a994272793;Parent=53995c3f42cd8ad8;Sampled=?"
+            .parse::<Header>()
+            .expect("invalid header");
+        let skipped = Segment::from_header_with("service", &header, || false);
+        assert!(!skipped.is_sampled());
+        assert_eq!(
+            skipped.response_header().sampling_decision,
+            SamplingDecision::NotSampled
+        );
+        let sampled = Segment::from_header_with("service", &header, || true);
+        assert!(sampled.is_sampled());
+        assert_eq!(
+            sampled.response_header().sampling_decision,
+            SamplingDecision::Sampled
+        );
+        let decided = "Root=1-5759e988-bd862e3fe1be46a994272793;Sampled=1"
+            .parse::<Header>()
+            .expect("invalid header");
+        assert!(
+            Segment::from_header_with("service", &decided, || panic!("decided twice")).is_sampled()
+        );
+    }
+
+    #[test]
+    fn requested_sampling_decisions_round_trip() {
+        let mut caller = Segment::builder("caller")
+            .sampling_decision(SamplingDecision::Requested)
+            .build();
+        let mut call = caller.begin_subsegment("call");
+        let request = call.header().expect("no header");
+        assert_eq!(request.sampling_decision, SamplingDecision::Requested);
+
+        let callee = Segment::from_header("callee", &request);
+        assert!(callee.is_sampled());
+        let response = callee
+            .response_header()
+            .to_string()
+            .parse::<Header>()
+            .expect("invalid header");
+        assert_eq!(response.sampling_decision, SamplingDecision::Sampled);
+
+        let mut unsampled = Header::new(caller.trace_id.clone());
+        unsampled.with_sampling_decision(SamplingDecision::NotSampled);
+        call.apply_sampling_decision(&unsampled);
+        caller.apply_sampling_decision(&unsampled);
+        assert!(!call.is_sampled() && !caller.is_sampled());
+        caller.apply_sampling_decision(&response);
+        assert!(!caller.is_sampled());
+    }
+
+    #[test]
+    fn sampling_decisions_of_other_traces_are_ignored() {
+        let mut caller = Segment::builder("caller")
+            .sampling_decision(SamplingDecision::Requested)
+            .build();
+        let mut response = Header::new(TraceId::new());
+        response.with_sampling_decision(SamplingDecision::NotSampled);
+        caller.apply_sampling_decision(&response);
+        assert_eq!(caller.sampling_decision, SamplingDecision::Requested);
+    }
+
+    #[test]
+    fn lineage_is_propagated_downstream() {
+        let header = "Root=1-5759e988-bd862e3fe1be46a994272793;Sampled=0;Lineage=a87bd80c:1"
+            .parse::<Header>()
+            .expect("invalid header");
+        let mut segment = Segment::from_header("function", &header);
+        segment.increment_lineage("a87bd80c");
+        let downstream = segment
+            .begin_subsegment("call")
+            .begin_subsegment("nested")
+            .header()
+            .expect("no header");
+        assert_eq!(
+            downstream.lineage().map(|lineage| lineage.to_string()),
+            Some("a87bd80c:2".into())
+        );
+    }
+
+    #[test]
+    fn embedded_subsegments_have_no_header() {
+        assert!(Subsegment::builder("embedded").build().header().is_none());
+    }
+
+    #[test]
+    fn in_progress_subsegments_are_added_as_is() {
+        let mut segment = Segment::begin("parent");
+        let child = segment.begin_subsegment("child");
+        segment.add_subsegment(child);
+        assert!(segment.subsegments[0].in_progress);
+    }
+
+    #[test]
+    fn spec_segment_with_embedded_subsegments() {
+        let mut segment = Segment {
+            trace_id: TraceId::Rendered("1-5759e988-bd862e3fe1be46a994272793".into()),
+            id: SegmentId::Rendered("defdfd9912dc5a56".into()),
+            start_time: Seconds(1_461_096_053.375_18),
+            end_time: Some(Seconds(1_461_096_053.404_2)),
+            name: "www.example.com".into(),
+            http: Some(Http {
+                request: Some(Request {
+                    url: Some("https://www.example.com/health".into()),
+                    method: Some("GET".into()),
+                    user_agent: Some(
+                        "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_11_6) AppleWebKit/601.7.7"
+                            .into(),
+                    ),
+                    client_ip: Some("11.0.3.111".into()),
+                    ..Request::default()
+                }),
+                response: Some(Response {
+                    status: Some(200),
+                    content_length: Some(86),
+                }),
+            }),
+            ..Segment::default()
+        };
+        let child = segment.begin_subsegment("api.example.com");
+        segment.end_subsegment(Subsegment {
+            id: SegmentId::Rendered("53995c3f42cd8ad8".into()),
+            start_time: Seconds(1_461_096_053.377_69),
+            end_time: Some(Seconds(1_461_096_053.403_79)),
+            in_progress: false,
+            namespace: Some("remote".into()),
+            http: Some(Http {
+                request: Some(Request {
+                    url: Some("https://api.example.com/health".into()),
+                    method: Some("POST".into()),
+                    traced: Some(true),
+                    ..Request::default()
+                }),
+                response: Some(Response {
+                    status: Some(200),
+                    content_length: Some(861),
+                }),
+            }),
+            ..child
+        });
+        assert_golden(
+            &segment,
+            json!({
+                "trace_id"   : "1-5759e988-bd862e3fe1be46a994272793",
+                "id"         : "defdfd9912dc5a56",
+                "start_time" : 1461096053.37518,
+                "end_time"   : 1461096053.4042,
+                "name"       : "www.example.com",
+                "http"       : {
+                    "request"  : {
+                        "url"        : "https://www.example.com/health",
+                        "method"     : "GET",
+                        "user_agent" : "Mozilla/5.0 (Macintosh; Intel Mac OS X 10_11_6) AppleWebKit/601.7.7",
+                        "client_ip"  : "11.0.3.111"
+                    },
+                    "response" : {
+                        "status"         : 200,
+                        "content_length" : 86
+                    }
+                },
+                "subsegments" : [
+                    {
+                        "id"         : "53995c3f42cd8ad8",
+                        "name"       : "api.example.com",
+                        "start_time" : 1461096053.37769,
+                        "end_time"   : 1461096053.40379,
+                        "namespace"  : "remote",
+                        "http"       : {
+                            "request"  : {
+                                "url"    : "https://api.example.com/health",
+                                "method" : "POST",
+                                "traced" : true
+                            },
+                            "response" : {
+                                "status"         : 200,
+                                "content_length" : 861
+                            }
+                        }
+                    }
+                ]
+            }),
+        );
+    }
+
+    #[test]
+    fn spec_in_progress_segment() {
+        assert_golden(
+            &Segment {
+                name: "example.com".into(),
+                id: SegmentId::Rendered("70de5b6f19ff9a0b".into()),
+                start_time: Seconds(1.478_293_361_271E9),
+                trace_id: TraceId::Rendered("1-581cf771-a006649127e371903a2de979".into()),
+                in_progress: true,
+                ..Segment::default()
+            },
+            json!({
+                "name" : "example.com",
+                "id" : "70de5b6f19ff9a0b",
+                "start_time" : 1.478293361271E9,
+                "trace_id" : "1-581cf771-a006649127e371903a2de979",
+                "in_progress": true
+            }),
+        )
+    }
+
+    #[test]
+    fn spec_segment_with_http_and_aws() {
+        assert_golden(
+            &Segment {
+                id: SegmentId::Rendered("6b55dcc497934f1a".into()),
+                start_time: Seconds(1_484_789_387.126),
+                end_time: Some(Seconds(1_484_789_387.535)),
+                trace_id: TraceId::Rendered("1-5880168b-fd5158284b67678a3bb5a78c".into()),
+                name: "www.example.com".into(),
+                origin: Some("AWS::EC2::Instance".into()),
+                aws: Some(Aws {
+                    ec2: Some(Ec2 {
+                        availability_zone: Some("us-west-2c".into()),
+                        instance_id: Some("i-0b5a4678fc325bg98".into()),
+                        ..Ec2::default()
+                    }),
+                    xray: Some(XRay {
+                        sdk_version: Some("2.11.0 for Java".into()),
+                        ..XRay::default()
+                    }),
+                    ..Aws::default()
+                }),
+                http: Some(Http {
+                    request: Some(Request {
+                        method: Some("POST".into()),
+                        client_ip: Some("78.255.233.48".into()),
+                        url: Some("http://www.example.com/api/user".into()),
+                        user_agent: Some("Mozilla/5.0 (Windows NT 6.1; Win64; x64; rv:45.0) Gecko/20100101 Firefox/45.0".into()),
+                        x_forwarded_for: Some(true),
+                        ..Request::default()
+                    }),
+                    response: Some(Response {
+                        status: Some(200),
+                        ..Response::default()
+                    }),
+                }),
+                ..Segment::default()
+            },
+            json!({
+                "id": "6b55dcc497934f1a",
+                "start_time": 1484789387.126,
+                "end_time": 1484789387.535,
+                "trace_id": "1-5880168b-fd5158284b67678a3bb5a78c",
+                "name": "www.example.com",
+                "origin": "AWS::EC2::Instance",
+                "aws": {
+                    "ec2": {
+                        "availability_zone": "us-west-2c",
+                        "instance_id": "i-0b5a4678fc325bg98"
+                    },
+                    "xray": {
+                        "sdk_version": "2.11.0 for Java"
+                    }
+                },
+                "http": {
+                    "request": {
+                        "method": "POST",
+                        "client_ip": "78.255.233.48",
+                        "url": "http://www.example.com/api/user",
+                        "user_agent": "Mozilla/5.0 (Windows NT 6.1; Win64; x64; rv:45.0) Gecko/20100101 Firefox/45.0",
+                        "x_forwarded_for": true
+                    },
+                    "response": {
+                        "status": 200
+                    }
+                }
+            }),
+        )
+    }
+
+    #[test]
+    fn spec_segment_with_environment() {
+        assert_golden(
+            &Segment {
+                name: "Scorekeep".into(),
+                id: SegmentId::Rendered("70de5b6f19ff9a0a".into()),
+                start_time: Seconds(1_478_293_361.271),
+                trace_id: TraceId::Rendered("1-581cf771-a006649127e371903a2de979".into()),
+                end_time: Some(Seconds(1_478_293_361.449)),
+                user: Some("5M388M1E".into()),
+                resource_arn: Some("arn:aws:elasticbeanstalk:us-west-2:123456789012:environment/scorekeep/scorekeep".into()),
+                service: Some(Service {
+                    version: Some("1.2.3".into()),
+                }),
+                aws: Some(Aws {
+                    elastic_beanstalk: Some(ElasticBeanstalk {
+                        version_label: Some("app-5a56-170119_190650-stage-170119_190650".into()),
+                        deployment_id: Some(32),
+                        environment_name: Some("scorekeep".into()),
+                    }),
+                    ecs: Some(Ecs {
+                        container: Some("ip-10-0-0-123".into()),
+                        ..Ecs::default()
+                    }),
+                    tracing: Some(Tracing {
+                        sdk: Some("1.0.0".into()),
+                    }),
+                    ..Aws::default()
+                }),
+                ..Segment::default()
+            },
+            json!({
+                "name": "Scorekeep",
+                "id": "70de5b6f19ff9a0a",
+                "start_time": 1478293361.271,
+                "trace_id": "1-581cf771-a006649127e371903a2de979",
+                "end_time": 1478293361.449,
+                "user": "5M388M1E",
+                "resource_arn": "arn:aws:elasticbeanstalk:us-west-2:123456789012:environment/scorekeep/scorekeep",
+                "service": {
+                    "version": "1.2.3"
+                },
+                "aws": {
+                    "elastic_beanstalk": {
+                        "version_label": "app-5a56-170119_190650-stage-170119_190650",
+                        "deployment_id": 32,
+                        "environment_name": "scorekeep"
+                    },
+                    "ecs": {
+                        "container": "ip-10-0-0-123"
+                    },
+                    "tracing": {
+                        "sdk": "1.0.0"
+                    }
+                }
+            }),
+        )
+    }
+
+    #[test]
+    fn spec_independent_subsegment() {
+        assert_golden(
+            &Subsegment {
+                namespace: Some("remote".into()),
+                http: Some(Http {
+                    request: Some(Request {
+                        url: Some("https://api.example.com/health".into()),
+                        method: Some("POST".into()),
+                        traced: Some(true),
+                        ..Request::default()
+                    }),
+                    response: Some(Response {
+                        status: Some(200),
+                        content_length: Some(861),
+                    }),
+                }),
+                ..independent(
+                    "api.example.com",
+                    "53995c3f42cd8ad8",
+                    1.478_293_361_271E9,
+                    1.478_293_361_449E9,
+                )
+            },
+            json!({
+                "name" : "api.example.com",
+                "id" : "53995c3f42cd8ad8",
+                "start_time" : 1.478293361271E9,
+                "end_time" : 1.478293361449E9,
+                "type" : "subsegment",
+                "trace_id" : "1-581cf771-a006649127e371903a2de979",
+                "parent_id" : "defdfd9912dc5a56",
+                "namespace" : "remote",
+                "http" : {
+                    "request" : {
+                        "url" : "https://api.example.com/health",
+                        "method" : "POST",
+                        "traced" : true
+                    },
+                    "response" : {
+                        "status" : 200,
+                        "content_length" : 861
+                    }
+                }
+            }),
+        )
+    }
+
+    #[test]
+    fn spec_annotations_and_metadata() {
+        let mut subsegment = independent(
+            "## UserModel.saveUser",
+            "0f910026178b71eb",
+            1_484_789_387.502,
+            1_484_789_387.534,
+        );
+        subsegment
+            .annotate("customer_category", 124)
+            .annotate("zip_code", 98101)
+            .annotate("country", "United States")
+            .annotate("internal", false)
+            .put_metadata("debug", "test", "Metadata string from UserModel.saveUser")
+            .expect("failed to put metadata");
+        assert_golden(
+            &subsegment,
+            json!({
+                "name": "## UserModel.saveUser",
+                "id": "0f910026178b71eb",
+                "start_time": 1484789387.502,
+                "end_time": 1484789387.534,
+                "type" : "subsegment",
+                "trace_id" : "1-581cf771-a006649127e371903a2de979",
+                "parent_id" : "defdfd9912dc5a56",
+                "annotations": {
+                    "customer_category" : 124,
+                    "zip_code" : 98101,
+                    "country" : "United States",
+                    "internal" : false
+                },
+                "metadata": {
+                    "debug": {
+                        "test": "Metadata string from UserModel.saveUser"
+                    }
+                }
+            }),
+        )
+    }
+
+    #[test]
+    fn spec_aws_sdk_subsegment() {
+        assert_golden(
+            &Subsegment {
+                namespace: Some("aws".into()),
+                http: Some(Http {
+                    response: Some(Response {
+                        content_length: Some(60),
+                        status: Some(200),
+                    }),
+                    ..Http::default()
+                }),
+                aws: Some(AwsOperation {
+                    table_name: Some("scorekeep-user".into()),
+                    operation: Some("UpdateItem".into()),
+                    request_id: Some("UBQNSO5AEM8T4FDA4RQDEB94OVTDRVV4K4HIRGVJF66Q9ASUAAJG".into()),
+                    ..AwsOperation::default()
+                }),
+                ..independent(
+                    "DynamoDB",
+                    "24756640c0d0978a",
+                    1.480_305_974_194E9,
+                    1.480_305_974_2E9,
+                )
+            },
+            json!({
+                "id": "24756640c0d0978a",
+                "start_time": 1.480305974194E9,
+                "end_time": 1.4803059742E9,
+                "name": "DynamoDB",
+                "type" : "subsegment",
+                "trace_id" : "1-581cf771-a006649127e371903a2de979",
+                "parent_id" : "defdfd9912dc5a56",
+                "namespace": "aws",
+                "http": {
+                    "response": {
+                        "content_length": 60,
+                        "status": 200
+                    }
+                },
+                "aws": {
+                    "table_name": "scorekeep-user",
+                    "operation": "UpdateItem",
+                    "request_id": "UBQNSO5AEM8T4FDA4RQDEB94OVTDRVV4K4HIRGVJF66Q9ASUAAJG"
+                }
+            }),
+        )
+    }
+
+    #[test]
+    fn aws_parameters_are_flattened() {
+        let mut aws = AwsOperation {
+            operation: Some("GetItem".into()),
+            region: Some("us-east-1".into()),
+            ..AwsOperation::default()
+        };
+        aws.parameters
+            .insert("consistent_read".into(), Value::Bool(true));
+        assert_eq!(
+            serde_json::to_value(&aws).expect("failed to serialize"),
+            json!({
+                "operation": "GetItem",
+                "region": "us-east-1",
+                "consistent_read": true
+            })
+        );
+    }
+
+    #[test]
+    fn spec_http_call_subsegment() {
+        assert_golden(
+            &Subsegment {
+                namespace: Some("remote".into()),
+                http: Some(Http {
+                    request: Some(Request {
+                        method: Some("GET".into()),
+                        url: Some("https://names.example.com/".into()),
+                        ..Request::default()
+                    }),
+                    response: Some(Response {
+                        content_length: Some(-1),
+                        status: Some(200),
+                    }),
+                }),
+                ..independent(
+                    "names.example.com",
+                    "004f72be19cddc2a",
+                    1_484_786_387.131,
+                    1_484_786_387.501,
+                )
+            },
+            json!({
+                "id": "004f72be19cddc2a",
+                "start_time": 1484786387.131,
+                "end_time": 1484786387.501,
+                "name": "names.example.com",
+                "type" : "subsegment",
+                "trace_id" : "1-581cf771-a006649127e371903a2de979",
+                "parent_id" : "defdfd9912dc5a56",
+                "namespace": "remote",
+                "http": {
+                    "request": {
+                        "method": "GET",
+                        "url": "https://names.example.com/"
+                    },
+                    "response": {
+                        "content_length": -1,
+                        "status": 200
+                    }
+                }
+            }),
+        )
+    }
+
+    #[test]
+    fn spec_sql_subsegment() {
+        assert_golden(
+            &Subsegment {
+                namespace: Some("remote".into()),
+                sql: Some(Sql {
+                    url: Some("jdbc:postgresql://aawijb5u25wdoy.cpamxznpdoq8.us-west-2.rds.amazonaws.com:5432/ebdb".into()),
+                    preparation: Some("statement".into()),
+                    database_type: Some("PostgreSQL".into()),
+                    database_version: Some("9.5.4".into()),
+                    driver_version: Some("PostgreSQL 9.4.1211.jre7".into()),
+                    user: Some("dbuser".into()),
+                    sanitized_query: Some("SELECT  *  FROM  customers  WHERE  customer_id=?;".into()),
+                    ..Sql::default()
+                }),
+                ..independent(
+                    "ebdb@aawijb5u25wdoy.cpamxznpdoq8.us-west-2.rds.amazonaws.com",
+                    "3fd8634e78ca9560",
+                    1_484_872_218.696,
+                    1_484_872_218.697,
+                )
+            },
+            json!({
+                "id": "3fd8634e78ca9560",
+                "start_time": 1484872218.696,
+                "end_time": 1484872218.697,
+                "name": "ebdb@aawijb5u25wdoy.cpamxznpdoq8.us-west-2.rds.amazonaws.com",
+                "type" : "subsegment",
+                "trace_id" : "1-581cf771-a006649127e371903a2de979",
+                "parent_id" : "defdfd9912dc5a56",
+                "namespace": "remote",
+                "sql" : {
+                    "url": "jdbc:postgresql://aawijb5u25wdoy.cpamxznpdoq8.us-west-2.rds.amazonaws.com:5432/ebdb",
+                    "preparation": "statement",
+                    "database_type": "PostgreSQL",
+                    "database_version": "9.5.4",
+                    "driver_version": "PostgreSQL 9.4.1211.jre7",
+                    "user" : "dbuser",
+                    "sanitized_query" : "SELECT  *  FROM  customers  WHERE  customer_id=?;"
+                }
+            }),
+        )
+    }
+
+    #[test]
+    fn spec_exception_subsegment() {
+        assert_golden(
+            &Subsegment {
+                fault: true,
+                throttled: true,
+                cause: Some(Cause::Description {
+                    working_directory: "/home/ec2-user/scorekeep".into(),
+                    paths: vec!["/var/app/current/scorekeep.jar".into()],
+                    exceptions: vec![Exception {
+                        id: "e3e5d5b4d6b7f9e6".into(),
+                        message: Some("Rate exceeded".into()),
+                        type_: Some("ThrottlingException".into()),
+                        remote: Some(true),
+                        truncated: Some(2),
+                        skipped: None,
+                        cause: None,
+                        stack: vec![StackFrame {
+                            path: Some("src/handler.rs".into()),
+                            line: Some(26),
+                            label: Some("scorekeep::handler".into()),
+                        }],
+                    }],
+                }),
+                ..independent(
+                    "DynamoDB",
+                    "24756640c0d0978a",
+                    1.480_305_974_194E9,
+                    1.480_305_974_2E9,
+                )
+            },
+            json!({
+                "id": "24756640c0d0978a",
+                "start_time": 1.480305974194E9,
+                "end_time": 1.4803059742E9,
+                "name": "DynamoDB",
+                "type" : "subsegment",
+                "trace_id" : "1-581cf771-a006649127e371903a2de979",
+                "parent_id" : "defdfd9912dc5a56",
+                "fault": true,
+                "throttle": true,
+                "cause": {
+                    "working_directory": "/home/ec2-user/scorekeep",
+                    "paths": ["/var/app/current/scorekeep.jar"],
+                    "exceptions": [
+                        {
+                            "id": "e3e5d5b4d6b7f9e6",
+                            "message": "Rate exceeded",
+                            "type": "ThrottlingException",
+                            "remote": true,
+                            "truncated": 2,
+                            "stack": [
+                                {
+                                    "path": "src/handler.rs",
+                                    "line": 26,
+                                    "label": "scorekeep::handler"
+                                }
+                            ]
+                        }
+                    ]
+                }
+            }),
+        )
+    }
 }
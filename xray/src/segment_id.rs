@@ -1,4 +1,4 @@
-use crate::hexbytes::Bytes;
+use crate::{hexbytes::Bytes, trace_id::is_hex};
 use rand::RngCore;
 use serde::{de, ser, Serializer};
 use std::fmt;
@@ -19,6 +19,14 @@ impl SegmentId {
         rand::thread_rng().fill_bytes(&mut buf);
         SegmentId::New(buf)
     }
+
+    /// Return true if this is a well formed 16 hex digit segment ID
+    pub(crate) fn is_valid(&self) -> bool {
+        match self {
+            SegmentId::New(_) => true,
+            SegmentId::Rendered(value) => is_hex(value, 16),
+        }
+    }
 }
 
 impl fmt::Display for SegmentId {
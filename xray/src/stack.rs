@@ -0,0 +1,249 @@
+//! Captures backtraces as X-Ray stack frames
+
+use crate::{Cause, Exception, StackFrame};
+use backtrace::Backtrace;
+use std::{
+    env,
+    path::{Path, PathBuf},
+};
+
+/// Symbol prefixes of frames which are not actionable for users of this crate
+const INTERNAL_PREFIXES: &[&str] = &[
+    "xray::",
+    "<xray::",
+    "backtrace::",
+    "<backtrace::",
+    "std::",
+    "<std::",
+    "core::",
+    "<core::",
+    "alloc::",
+    "<alloc::",
+    "__rust",
+    "rust_begin_unwind",
+    "rust_panic",
+    "__libc_start",
+    "_start",
+];
+
+/// Opt-in capture of the current thread's stack for exceptions
+///
+/// Frames from this crate and from the Rust runtime are omitted and paths are made
+/// relative to a root directory. The root defaults to the `CARGO_MANIFEST_DIR` cargo sets
+/// when running your crate. Binaries run outside of cargo should set it with
+/// `root(env!("CARGO_MANIFEST_DIR"))`, otherwise paths are left absolute.
+#[derive(Debug, Clone)]
+pub struct StackCapture {
+    max_depth: usize,
+    root: Option<PathBuf>,
+}
+
+impl Default for StackCapture {
+    fn default() -> Self {
+        StackCapture {
+            max_depth: StackCapture::DEFAULT_MAX_DEPTH,
+            root: env::var_os("CARGO_MANIFEST_DIR").map(PathBuf::from),
+        }
+    }
+}
+
+impl StackCapture {
+    /// Default maximum number of frames recorded per stack
+    pub const DEFAULT_MAX_DEPTH: usize = 32;
+
+    /// Return a new stack capture with default settings
+    pub fn new() -> Self {
+        StackCapture::default()
+    }
+
+    /// Set the maximum number of frames recorded. Frames beyond this are counted as truncated
+    pub fn max_depth(
+        mut self,
+        max_depth: usize,
+    ) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    /// Set the directory frame paths are made relative to, typically your crate's `CARGO_MANIFEST_DIR`
+    pub fn root<P>(
+        mut self,
+        root: P,
+    ) -> Self
+    where
+        P: Into<PathBuf>,
+    {
+        self.root = Some(root.into());
+        self
+    }
+
+    /// Capture the current stack into an exception's `stack`, setting its
+    /// `truncated` count when frames were omitted beyond the maximum depth
+    pub fn capture(
+        &self,
+        exception: &mut Exception,
+    ) {
+        self.record(resolve(&Backtrace::new()), exception)
+    }
+
+    fn record<I>(
+        &self,
+        symbols: I,
+        exception: &mut Exception,
+    ) where
+        I: IntoIterator<Item = Symbol>,
+    {
+        let mut frames = symbols
+            .into_iter()
+            .filter(|symbol| !symbol.label.iter().any(|label| is_internal(label)))
+            .map(|symbol| StackFrame {
+                path: symbol.path.map(|path| relative(&path, self.root.as_ref())),
+                line: symbol.line,
+                label: symbol.label,
+            })
+            .collect::<Vec<_>>();
+        let truncated = frames.len().saturating_sub(self.max_depth);
+        frames.truncate(self.max_depth);
+        exception.stack = frames;
+        exception.truncated = if truncated > 0 { Some(truncated) } else { None };
+    }
+}
+
+/// A resolved frame of a backtrace
+struct Symbol {
+    label: Option<String>,
+    path: Option<PathBuf>,
+    line: Option<u32>,
+}
+
+fn resolve(backtrace: &Backtrace) -> Vec<Symbol> {
+    backtrace
+        .frames()
+        .iter()
+        .flat_map(|frame| frame.symbols())
+        .map(|symbol| Symbol {
+            label: symbol.name().map(|name| format!("{:#}", name)),
+            path: symbol.filename().map(Path::to_path_buf),
+            line: symbol.lineno(),
+        })
+        .collect()
+}
+
+impl Cause {
+    /// Capture the current stack into this cause's outermost exception
+    pub fn with_stack(
+        mut self,
+        capture: &StackCapture,
+    ) -> Self {
+        if let Cause::Description { exceptions, .. } = &mut self {
+            if let Some(exception) = exceptions.first_mut() {
+                capture.capture(exception);
+            }
+        }
+        self
+    }
+}
+
+fn is_internal(label: &str) -> bool {
+    INTERNAL_PREFIXES
+        .iter()
+        .any(|prefix| label.starts_with(prefix))
+}
+
+fn relative(
+    path: &Path,
+    root: Option<&PathBuf>,
+) -> String {
+    root.and_then(|root| path.strip_prefix(root).ok())
+        .unwrap_or(path)
+        .display()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    #[test]
+    fn internal_frames_are_detected() {
+        assert!(is_internal("xray::stack::StackCapture::capture"));
+        assert!(is_internal("std::rt::lang_start"));
+        assert!(is_internal(
+            "<alloc::boxed::Box<F> as core::ops::FnOnce<A>>::call_once"
+        ));
+        assert!(!is_internal("app::handler"));
+    }
+
+    #[test]
+    fn paths_are_relative_to_root() {
+        assert_eq!(
+            relative(
+                Path::new("/src/app/src/main.rs"),
+                Some(&PathBuf::from("/src/app"))
+            ),
+            "src/main.rs"
+        );
+        assert_eq!(
+            relative(Path::new("/other/lib.rs"), Some(&PathBuf::from("/src/app"))),
+            "/other/lib.rs"
+        );
+    }
+
+    fn symbol(label: &str) -> Symbol {
+        Symbol {
+            label: Some(label.into()),
+            path: Some(PathBuf::from("/src/app/src/main.rs")),
+            line: Some(1),
+        }
+    }
+
+    #[test]
+    fn record_omits_internal_frames_and_limits_depth() {
+        let mut exception = Exception::new("io");
+        StackCapture::new().max_depth(2).root("/src/app").record(
+            vec![
+                symbol("backtrace::Backtrace::new"),
+                symbol("xray::stack::StackCapture::capture"),
+                symbol("app::query"),
+                symbol("app::handler"),
+                symbol("app::main"),
+                symbol("std::rt::lang_start"),
+            ],
+            &mut exception,
+        );
+        assert_eq!(
+            exception
+                .stack
+                .iter()
+                .map(|frame| frame.label.as_ref().map(String::as_str))
+                .collect::<Vec<_>>(),
+            vec![Some("app::query"), Some("app::handler")]
+        );
+        assert_eq!(
+            exception.stack[0].path.as_ref().map(String::as_str),
+            Some("src/main.rs")
+        );
+        assert_eq!(exception.truncated, Some(1));
+    }
+
+    #[test]
+    fn record_within_depth_is_not_truncated() {
+        let mut exception = Exception::new("io");
+        StackCapture::new()
+            .max_depth(2)
+            .record(vec![symbol("app::main")], &mut exception);
+        assert_eq!(exception.stack.len(), 1);
+        assert_eq!(exception.truncated, None);
+    }
+
+    #[test]
+    fn capture_resolves_the_current_stack() {
+        let mut cause = Cause::from_error(&io::Error::new(io::ErrorKind::NotFound, "io"))
+            .with_stack(&StackCapture::new().max_depth(1));
+        if let Cause::Description { exceptions, .. } = &mut cause {
+            assert!(exceptions[0].stack.len() <= 1);
+        }
+        assert!(!resolve(&Backtrace::new()).is_empty());
+    }
+}
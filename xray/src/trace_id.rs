@@ -23,6 +23,27 @@ impl TraceId {
         rand::thread_rng().fill_bytes(&mut buf);
         TraceId::New(Seconds::now().trunc(), buf)
     }
+
+    /// Return true if this is a well formed `1-{8 hex digits}-{24 hex digits}` trace ID
+    pub(crate) fn is_valid(&self) -> bool {
+        match self {
+            TraceId::New(..) => true,
+            TraceId::Rendered(value) => {
+                let parts: Vec<_> = value.split('-').collect();
+                match parts.as_slice() {
+                    ["1", time, id] => is_hex(time, 8) && is_hex(id, 24),
+                    _ => false,
+                }
+            }
+        }
+    }
+}
+
+pub(crate) fn is_hex(
+    value: &str,
+    len: usize,
+) -> bool {
+    value.len() == len && value.chars().all(|c| c.is_ascii_hexdigit())
 }
 
 impl Default for TraceId {
@@ -0,0 +1,652 @@
+//! Checks segment documents against the rules the X-Ray API enforces
+//! before they are sent to the daemon
+
+use crate::{Annotation, Seconds, Segment, SegmentId, Subsegment};
+use serde::Serialize;
+use std::{collections::HashMap, fmt};
+
+/// Maximum number of characters in a segment or subsegment name
+pub(crate) const MAX_NAME_LEN: usize = 200;
+
+/// Maximum number of characters in an annotation key
+pub(crate) const MAX_ANNOTATION_KEY_LEN: usize = 500;
+
+/// Maximum number of annotations indexed per document
+pub(crate) const MAX_ANNOTATIONS: usize = 50;
+
+/// Returns true if X-Ray accepts `c` within a segment name
+///
+/// Names can contain Unicode letters, numbers, and whitespace, and the following symbols: _, ., :, /, %, &, #, =, +, \, -, @
+pub(crate) fn is_name_char(c: char) -> bool {
+    c.is_alphanumeric() || (c.is_whitespace() && !c.is_control()) || "_.:/%&#=+\\-@".contains(c)
+}
+
+/// Returns true if X-Ray accepts `c` within an annotation key
+pub(crate) fn is_annotation_key_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// Truncates `value` to at most `max` characters without splitting a character
+pub(crate) fn truncate(
+    value: &mut String,
+    max: usize,
+) {
+    if let Some((pos, _)) = value.char_indices().nth(max) {
+        value.truncate(pos);
+    }
+}
+
+/// Truncates `name` to X-Ray's maximum name length and replaces characters
+/// X-Ray does not allow with `_`
+pub(crate) fn sanitize_name(name: &mut String) {
+    truncate(name, MAX_NAME_LEN);
+    if name.chars().any(|c| !is_name_char(c)) {
+        *name = name
+            .chars()
+            .map(|c| if is_name_char(c) { c } else { '_' })
+            .collect();
+    }
+}
+
+/// Truncates `key` to X-Ray's maximum annotation key length and replaces characters
+/// other than ASCII letters, numbers and underscores with `_`
+pub(crate) fn sanitize_annotation_key(key: &mut String) {
+    if key.chars().any(|c| !is_annotation_key_char(c)) {
+        *key = key
+            .chars()
+            .map(|c| if is_annotation_key_char(c) { c } else { '_' })
+            .collect();
+    }
+    truncate(key, MAX_ANNOTATION_KEY_LEN);
+}
+
+/// How a `Client` treats documents which break X-Ray rules
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Validation {
+    /// Send documents as they are
+    Skip,
+    /// Refuse to send documents with violations
+    Refuse,
+    /// Repair what can be repaired and refuse to send documents with remaining violations
+    Repair,
+}
+
+impl Default for Validation {
+    fn default() -> Self {
+        Validation::Skip
+    }
+}
+
+/// A rule broken by a segment document
+#[derive(Debug, Clone, PartialEq)]
+pub struct Violation {
+    /// The id of the segment or subsegment breaking the rule
+    pub id: SegmentId,
+    /// The rule which was broken
+    pub kind: ViolationKind,
+}
+
+/// The kinds of rules X-Ray enforces on segment documents
+#[derive(Debug, Clone, PartialEq)]
+pub enum ViolationKind {
+    /// The name is empty
+    EmptyName,
+    /// The name is longer than 200 characters. Holds the number of characters
+    NameTooLong(usize),
+    /// The name contains a character X-Ray does not allow
+    InvalidNameChar(char),
+    /// An annotation key is empty, longer than 500 characters or contains characters other than ASCII letters, numbers and underscores
+    InvalidAnnotationKey(String),
+    /// More than 50 annotations were recorded. Holds the number of annotations
+    TooManyAnnotations(usize),
+    /// Neither an `end_time` nor `in_progress` was set
+    MissingEndTime,
+    /// The `end_time` is earlier than the `start_time`
+    EndBeforeStart,
+    /// An independently sent subsegment has no `trace_id`
+    MissingTraceId,
+    /// An independently sent subsegment has no `parent_id`
+    MissingParentId,
+    /// A segment sets a field which only applies to subsegments
+    SubsegmentOnlyField(&'static str),
+    /// A subsegment sets a field which only applies to segments
+    SegmentOnlyField(&'static str),
+}
+
+impl fmt::Display for ViolationKind {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter,
+    ) -> fmt::Result {
+        match self {
+            ViolationKind::EmptyName => write!(f, "name is empty"),
+            ViolationKind::NameTooLong(len) => write!(
+                f,
+                "name is {} characters long, the maximum is {}",
+                len, MAX_NAME_LEN
+            ),
+            ViolationKind::InvalidNameChar(c) => {
+                write!(f, "name contains invalid character {:?}", c)
+            }
+            ViolationKind::InvalidAnnotationKey(key) => {
+                write!(f, "invalid annotation key {:?}", key)
+            }
+            ViolationKind::TooManyAnnotations(count) => write!(
+                f,
+                "{} annotations were recorded, the maximum is {}",
+                count, MAX_ANNOTATIONS
+            ),
+            ViolationKind::MissingEndTime => write!(f, "neither end_time nor in_progress is set"),
+            ViolationKind::EndBeforeStart => write!(f, "end_time is earlier than start_time"),
+            ViolationKind::MissingTraceId => write!(f, "independent subsegment has no trace_id"),
+            ViolationKind::MissingParentId => write!(f, "independent subsegment has no parent_id"),
+            ViolationKind::SubsegmentOnlyField(field) => {
+                write!(f, "{} only applies to subsegments", field)
+            }
+            ViolationKind::SegmentOnlyField(field) => {
+                write!(f, "{} only applies to segments", field)
+            }
+        }
+    }
+}
+
+impl fmt::Display for Violation {
+    fn fmt(
+        &self,
+        f: &mut fmt::Formatter,
+    ) -> fmt::Result {
+        write!(f, "{}: {}", self.id, self.kind)
+    }
+}
+
+/// A segment document which can be checked and repaired before it's sent to X-Ray
+///
+/// This trait is sealed and implemented for `Segment`, `Subsegment` and `context::Active`
+pub trait Document: Serialize + Clone + sealed::Sealed {
+    /// Returns the rules this document breaks, if any
+    fn validate(&self) -> Result<(), Vec<Violation>>;
+
+    /// Repairs the violations that can be fixed without guessing at missing information
+    ///
+    /// Names are truncated and have invalid characters replaced, annotation keys are
+    /// sanitized, annotations beyond the limit are dropped, missing end times are set to
+    /// the current time and end times earlier than their start time are moved to the start time.
+    fn repair(&mut self);
+
+    /// Returns the id of this document
+    fn id(&self) -> &SegmentId;
+}
+
+pub(crate) mod sealed {
+    use crate::Subsegment;
+
+    /// How a `Client` sends a document, kept out of reach of other crates
+    /// so `Document` can't be implemented outside of this one
+    pub trait Sealed: Sized {
+        /// Returns true if this document has been started but not yet completed
+        fn is_in_progress(&self) -> bool;
+
+        /// Returns a copy of this document marked as in progress, without an end time
+        fn to_in_progress(&self) -> Self;
+
+        /// Returns false if this document belongs to a trace which was not sampled
+        fn is_sampled(&self) -> bool;
+
+        /// Returns the subsegments to send in place of this document if it is a facade
+        /// for a segment recorded by AWS Lambda
+        fn facade_subsegments(&self) -> Option<Vec<Subsegment>> {
+            None
+        }
+    }
+}
+
+impl Document for Segment {
+    fn validate(&self) -> Result<(), Vec<Violation>> {
+        let mut violations = Vec::new();
+        check(
+            &self.id,
+            &self.name,
+            &self.start_time,
+            &self.end_time,
+            self.in_progress,
+            &self.annotations,
+            &mut violations,
+        );
+        if let Some(request) = self.http.as_ref().and_then(|http| http.request.as_ref()) {
+            if request.traced.is_some() {
+                violations.push(Violation {
+                    id: self.id.clone(),
+                    kind: ViolationKind::SubsegmentOnlyField("http.request.traced"),
+                });
+            }
+        }
+        for child in &self.subsegments {
+            check_subsegment(child, &mut violations);
+        }
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(violations)
+        }
+    }
+
+    fn repair(&mut self) {
+        repair(
+            &mut self.name,
+            &self.start_time,
+            &mut self.end_time,
+            self.in_progress,
+            &mut self.annotations,
+        );
+        if let Some(request) = self.http.as_mut().and_then(|http| http.request.as_mut()) {
+            request.traced = None;
+        }
+        for child in &mut self.subsegments {
+            repair_subsegment(child);
+        }
+    }
+
+    fn id(&self) -> &SegmentId {
+        &self.id
+    }
+}
+
+impl sealed::Sealed for Segment {
+    fn is_in_progress(&self) -> bool {
+        self.in_progress
+    }
+
+    fn is_sampled(&self) -> bool {
+        self.is_sampled()
+    }
+
+    fn facade_subsegments(&self) -> Option<Vec<Subsegment>> {
+        if !self.facade {
+            return None;
+        }
+        Some(
+            self.subsegments
+                .iter()
+                .map(|subsegment| Subsegment {
+                    trace_id: Some(self.trace_id.clone()),
+                    parent_id: Some(self.id.clone()),
+                    type_: "subsegment".into(),
+                    ..subsegment.clone()
+                })
+                .collect(),
+        )
+    }
+
+    fn to_in_progress(&self) -> Self {
+        Segment {
+            end_time: None,
+            in_progress: true,
+            ..self.clone()
+        }
+    }
+}
+
+impl Document for Subsegment {
+    fn validate(&self) -> Result<(), Vec<Violation>> {
+        let mut violations = Vec::new();
+        if self.trace_id.is_none() {
+            violations.push(Violation {
+                id: self.id.clone(),
+                kind: ViolationKind::MissingTraceId,
+            });
+        }
+        if self.parent_id.is_none() {
+            violations.push(Violation {
+                id: self.id.clone(),
+                kind: ViolationKind::MissingParentId,
+            });
+        }
+        check_subsegment(self, &mut violations);
+        if violations.is_empty() {
+            Ok(())
+        } else {
+            Err(violations)
+        }
+    }
+
+    fn repair(&mut self) {
+        repair_subsegment(self);
+    }
+
+    fn id(&self) -> &SegmentId {
+        &self.id
+    }
+}
+
+impl sealed::Sealed for Subsegment {
+    fn is_in_progress(&self) -> bool {
+        self.in_progress
+    }
+
+    fn is_sampled(&self) -> bool {
+        self.is_sampled()
+    }
+
+    fn to_in_progress(&self) -> Self {
+        Subsegment {
+            end_time: None,
+            in_progress: true,
+            ..self.clone()
+        }
+    }
+}
+
+fn check_subsegment(
+    subsegment: &Subsegment,
+    violations: &mut Vec<Violation>,
+) {
+    check(
+        &subsegment.id,
+        &subsegment.name,
+        &subsegment.start_time,
+        &subsegment.end_time,
+        subsegment.in_progress,
+        &subsegment.annotations,
+        violations,
+    );
+    if let Some(request) = subsegment
+        .http
+        .as_ref()
+        .and_then(|http| http.request.as_ref())
+    {
+        if request.x_forwarded_for.is_some() {
+            violations.push(Violation {
+                id: subsegment.id.clone(),
+                kind: ViolationKind::SegmentOnlyField("http.request.x_forwarded_for"),
+            });
+        }
+    }
+    for child in &subsegment.subsegments {
+        check_subsegment(child, violations);
+    }
+}
+
+fn repair_subsegment(subsegment: &mut Subsegment) {
+    repair(
+        &mut subsegment.name,
+        &subsegment.start_time,
+        &mut subsegment.end_time,
+        subsegment.in_progress,
+        &mut subsegment.annotations,
+    );
+    if let Some(request) = subsegment
+        .http
+        .as_mut()
+        .and_then(|http| http.request.as_mut())
+    {
+        request.x_forwarded_for = None;
+    }
+    for child in &mut subsegment.subsegments {
+        repair_subsegment(child);
+    }
+}
+
+fn check(
+    id: &SegmentId,
+    name: &str,
+    start_time: &Seconds,
+    end_time: &Option<Seconds>,
+    in_progress: bool,
+    annotations: &Option<HashMap<String, Annotation>>,
+    violations: &mut Vec<Violation>,
+) {
+    let mut push = |kind| {
+        violations.push(Violation {
+            id: id.clone(),
+            kind,
+        })
+    };
+    let len = name.chars().count();
+    if len == 0 {
+        push(ViolationKind::EmptyName);
+    }
+    if len > MAX_NAME_LEN {
+        push(ViolationKind::NameTooLong(len));
+    }
+    if let Some(c) = name.chars().find(|c| !is_name_char(*c)) {
+        push(ViolationKind::InvalidNameChar(c));
+    }
+    if let Some(annotations) = annotations {
+        if annotations.len() > MAX_ANNOTATIONS {
+            push(ViolationKind::TooManyAnnotations(annotations.len()));
+        }
+        for key in annotations.keys() {
+            if key.is_empty()
+                || key.chars().count() > MAX_ANNOTATION_KEY_LEN
+                || !key.chars().all(is_annotation_key_char)
+            {
+                push(ViolationKind::InvalidAnnotationKey(key.clone()));
+            }
+        }
+    }
+    match end_time {
+        Some(end) if end.0 < start_time.0 => push(ViolationKind::EndBeforeStart),
+        None if !in_progress => push(ViolationKind::MissingEndTime),
+        _ => (),
+    }
+}
+
+fn repair(
+    name: &mut String,
+    start_time: &Seconds,
+    end_time: &mut Option<Seconds>,
+    in_progress: bool,
+    annotations: &mut Option<HashMap<String, Annotation>>,
+) {
+    sanitize_name(name);
+    if let Some(annotations) = annotations.as_mut() {
+        let mut keys = annotations.keys().cloned().collect::<Vec<_>>();
+        keys.sort();
+        let mut repaired = HashMap::new();
+        for key in keys.into_iter().take(MAX_ANNOTATIONS) {
+            if let Some(value) = annotations.remove(&key) {
+                let mut valid_key = key;
+                sanitize_annotation_key(&mut valid_key);
+                if !valid_key.is_empty() {
+                    repaired.insert(valid_key, value);
+                }
+            }
+        }
+        *annotations = repaired;
+    }
+    match end_time {
+        Some(end) if end.0 < start_time.0 => *end = Seconds(start_time.0),
+        None if !in_progress => *end_time = Some(Seconds::now()),
+        _ => (),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{Http, Request, TraceId};
+
+    fn segment() -> Segment {
+        Segment {
+            name: "valid".into(),
+            id: SegmentId::Rendered("70de5b6f19ff9a0a".into()),
+            start_time: Seconds(1_478_293_361.271),
+            end_time: Some(Seconds(1_478_293_361.449)),
+            ..Segment::default()
+        }
+    }
+
+    fn kinds<D: Document>(doc: &D) -> Vec<ViolationKind> {
+        doc.validate()
+            .err()
+            .unwrap_or_default()
+            .into_iter()
+            .map(|violation| violation.kind)
+            .collect()
+    }
+
+    #[test]
+    fn truncates_on_char_boundaries() {
+        let mut value = "ü".repeat(3);
+        truncate(&mut value, 2);
+        assert_eq!(value, "üü");
+    }
+
+    #[test]
+    fn valid_segments_have_no_violations() {
+        assert_eq!(segment().validate(), Ok(()));
+    }
+
+    #[test]
+    fn segments_with_invalid_names_have_violations() {
+        assert_eq!(
+            kinds(&Segment {
+                name: "".into(),
+                ..segment()
+            }),
+            vec![ViolationKind::EmptyName]
+        );
+        assert_eq!(
+            kinds(&Segment {
+                name: "X".repeat(201),
+                ..segment()
+            }),
+            vec![ViolationKind::NameTooLong(201)]
+        );
+        assert_eq!(
+            kinds(&Segment {
+                name: "semi;colon".into(),
+                ..segment()
+            }),
+            vec![ViolationKind::InvalidNameChar(';')]
+        );
+    }
+
+    #[test]
+    fn segments_with_invalid_annotations_have_violations() {
+        let mut annotations = HashMap::new();
+        annotations.insert("not-valid".to_string(), Annotation::Bool(true));
+        assert_eq!(
+            kinds(&Segment {
+                annotations: Some(annotations),
+                ..segment()
+            }),
+            vec![ViolationKind::InvalidAnnotationKey("not-valid".into())]
+        );
+        let annotations = (0..51)
+            .map(|i| (format!("key_{}", i), Annotation::Bool(true)))
+            .collect();
+        assert_eq!(
+            kinds(&Segment {
+                annotations: Some(annotations),
+                ..segment()
+            }),
+            vec![ViolationKind::TooManyAnnotations(51)]
+        );
+    }
+
+    #[test]
+    fn segments_with_invalid_times_have_violations() {
+        assert_eq!(
+            kinds(&Segment {
+                end_time: None,
+                ..segment()
+            }),
+            vec![ViolationKind::MissingEndTime]
+        );
+        assert_eq!(
+            kinds(&Segment {
+                end_time: None,
+                in_progress: true,
+                ..segment()
+            }),
+            vec![]
+        );
+        assert_eq!(
+            kinds(&Segment {
+                end_time: Some(Seconds(1_478_293_361.0)),
+                ..segment()
+            }),
+            vec![ViolationKind::EndBeforeStart]
+        );
+    }
+
+    #[test]
+    fn independent_subsegments_require_trace_and_parent_ids() {
+        let mut subsegment = Subsegment::begin(TraceId::new(), None, "valid");
+        subsegment.trace_id = None;
+        subsegment.end();
+        assert_eq!(
+            kinds(&subsegment),
+            vec![
+                ViolationKind::MissingTraceId,
+                ViolationKind::MissingParentId
+            ]
+        );
+    }
+
+    #[test]
+    fn misplaced_request_fields_have_violations() {
+        let segment = Segment {
+            http: Some(Http {
+                request: Some(Request {
+                    traced: Some(true),
+                    ..Request::default()
+                }),
+                ..Http::default()
+            }),
+            ..segment()
+        };
+        assert_eq!(
+            kinds(&segment),
+            vec![ViolationKind::SubsegmentOnlyField("http.request.traced")]
+        );
+        let mut subsegment = Subsegment::begin(TraceId::new(), Some(SegmentId::new()), "valid");
+        subsegment.end();
+        subsegment.http = Some(Http {
+            request: Some(Request {
+                x_forwarded_for: Some(true),
+                ..Request::default()
+            }),
+            ..Http::default()
+        });
+        assert_eq!(
+            kinds(&subsegment),
+            vec![ViolationKind::SegmentOnlyField(
+                "http.request.x_forwarded_for"
+            )]
+        );
+    }
+
+    #[test]
+    fn segments_validate_nested_subsegments() {
+        let mut child = Subsegment::begin(TraceId::new(), None, "child");
+        child.trace_id = None;
+        child.in_progress = false;
+        let segment = Segment {
+            subsegments: vec![child],
+            ..segment()
+        };
+        assert_eq!(kinds(&segment), vec![ViolationKind::MissingEndTime]);
+    }
+
+    #[test]
+    fn repair_fixes_repairable_violations() {
+        let mut annotations = HashMap::new();
+        annotations.insert("not-valid".to_string(), Annotation::Bool(true));
+        let mut segment = Segment {
+            name: "semi;colon".into(),
+            end_time: Some(Seconds(1_478_293_361.0)),
+            annotations: Some(annotations),
+            ..segment()
+        };
+        segment.repair();
+        assert_eq!(segment.validate(), Ok(()));
+        assert_eq!(segment.name, "semi_colon");
+        assert_eq!(segment.end_time, Some(Seconds(1_478_293_361.271)));
+        assert!(segment
+            .annotations
+            .unwrap_or_default()
+            .contains_key("not_valid"));
+    }
+}